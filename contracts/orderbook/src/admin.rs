@@ -0,0 +1,175 @@
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::{assert_one_yocto, env, near_bindgen, AccountId};
+
+use crate::{emit_event, Contract, ContractExt, Role};
+
+/// Whether the contract accepts trading. `Paused` stops order placement and
+/// matching; `Emergency` also refuses deposits, leaving only cancels and
+/// withdrawals.
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, PartialEq, Debug)]
+#[borsh(crate = "near_sdk::borsh")]
+pub enum ContractStatus {
+    Running,
+    Paused,
+    Emergency,
+}
+
+fn contract_status_str(status: ContractStatus) -> &'static str {
+    match status {
+        ContractStatus::Running => "running",
+        ContractStatus::Paused => "paused",
+        ContractStatus::Emergency => "emergency",
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Stops order placement and matching until `unpause`. Owner or pauser.
+    #[payable]
+    pub fn pause(&mut self) {
+        assert_one_yocto();
+        self.assert_role(Role::Pauser);
+        assert_eq!(self.status, ContractStatus::Running, "contract is not running");
+        self.internal_set_status(ContractStatus::Paused);
+    }
+
+    /// Resumes trading from either `Paused` or `Emergency`.
+    #[payable]
+    pub fn unpause(&mut self) {
+        assert_one_yocto();
+        self.assert_role(Role::Pauser);
+        assert_ne!(self.status, ContractStatus::Running, "contract is already running");
+        self.internal_set_status(ContractStatus::Running);
+    }
+
+    /// Like `pause`, but deposits are refused too so funds can only leave.
+    #[payable]
+    pub fn enable_emergency(&mut self) {
+        assert_one_yocto();
+        self.assert_role(Role::Pauser);
+        assert_ne!(self.status, ContractStatus::Emergency, "contract is already in emergency mode");
+        self.internal_set_status(ContractStatus::Emergency);
+    }
+
+    #[payable]
+    pub fn set_owner(&mut self, owner_id: AccountId) {
+        self.assert_owner();
+        emit_event(
+            "owner_change",
+            near_sdk::serde_json::json!({
+                "old_owner_id": self.owner_id,
+                "new_owner_id": owner_id,
+            }),
+        );
+        self.owner_id = owner_id;
+    }
+
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    /// `running`, `paused` or `emergency`.
+    pub fn get_status(&self) -> String {
+        contract_status_str(self.status).to_string()
+    }
+}
+
+impl Contract {
+    pub(crate) fn assert_owner(&self) {
+        assert_one_yocto();
+        assert_eq!(env::predecessor_account_id(), self.owner_id, "Only owner");
+    }
+
+    /// Trading entry points: placing, triggering and executing orders.
+    pub(crate) fn assert_running(&self) {
+        match self.status {
+            ContractStatus::Running => {}
+            ContractStatus::Paused => env::panic_str("contract is paused"),
+            ContractStatus::Emergency => env::panic_str("contract is in emergency mode"),
+        }
+    }
+
+    pub(crate) fn is_emergency(&self) -> bool {
+        self.status == ContractStatus::Emergency
+    }
+
+    fn internal_set_status(&mut self, status: ContractStatus) {
+        emit_event(
+            "status_change",
+            near_sdk::serde_json::json!({
+                "old_status": contract_status_str(self.status),
+                "new_status": contract_status_str(status),
+                "by": env::predecessor_account_id(),
+            }),
+        );
+        self.status = status;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_sdk::json_types::U128;
+    use near_sdk::PromiseOrValue;
+
+    use super::*;
+    use crate::test_utils::{acc, context, deposit, new_market, register};
+    use crate::MarketId;
+
+    /// Alice rests a sell of 5 of her 10 base, then the owner pauses.
+    fn paused_market() -> (Contract, MarketId) {
+        let (mut contract, market_id) = new_market();
+        register(&mut contract, "alice.near");
+        deposit(&mut contract, "alice.near", "base.near", 10);
+        context("alice.near");
+        contract.place_order(market_id, "sell".into(), U128(5), None, U128(1), U128(1), None, None, None, None, None, None);
+        context("owner.near");
+        contract.pause();
+        (contract, market_id)
+    }
+
+    #[test]
+    #[should_panic(expected = "contract is paused")]
+    fn paused_blocks_placing_orders() {
+        let (mut contract, market_id) = paused_market();
+        context("alice.near");
+        contract.place_order(market_id, "sell".into(), U128(5), None, U128(1), U128(1), None, None, None, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "contract is paused")]
+    fn paused_blocks_execute() {
+        let (mut contract, _) = paused_market();
+        context("owner.near");
+        contract.execute(0, 1, U128(1), U128(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "contract is paused")]
+    fn paused_blocks_triggering_stops() {
+        let (mut contract, market_id) = paused_market();
+        context("bob.near");
+        contract.trigger_stops(market_id, None);
+    }
+
+    #[test]
+    fn paused_still_cancels_and_withdraws() {
+        let (mut contract, _) = paused_market();
+        context("alice.near");
+        contract.cancel_order(0);
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("base.near")).0, 10);
+        contract.withdraw(acc("base.near"), U128(10), None, None).detach();
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("base.near")).0, 0);
+    }
+
+    #[test]
+    fn emergency_refuses_deposits() {
+        let (mut contract, _) = paused_market();
+        context("owner.near");
+        contract.enable_emergency();
+        context("base.near");
+        let refund = contract.ft_on_transfer(acc("alice.near"), U128(7), String::new());
+        assert!(matches!(refund, PromiseOrValue::Value(U128(7))));
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("base.near")).0, 5);
+    }
+}
@@ -0,0 +1,304 @@
+use std::cmp::Ordering;
+
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, TreeMap};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::IntoStorageKey;
+
+use crate::math::{gcd, mul_cmp};
+use crate::{Order, Side};
+
+/// Limit price `num / den` (quote per unit base) used as a book level key.
+/// Prices enter the contract through `new`, in lowest terms, so equal
+/// values share one level. Levels are ordered by economic value, tie-broken
+/// on `num` so the order stays total for any unreduced key.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct Price {
+    pub num: u128,
+    pub den: u128,
+}
+
+impl Price {
+    /// `num / den` in lowest terms.
+    pub fn new(num: u128, den: u128) -> Self {
+        let g = gcd(num, den);
+        Self { num: num / g, den: den / g }
+    }
+
+    pub fn of(order: &Order) -> Self {
+        Self { num: order.price_num.0, den: order.price_den.0 }
+    }
+
+    /// Compares economic value only, ignoring representation.
+    pub fn cmp_value(&self, other: &Self) -> Ordering {
+        mul_cmp(self.num, other.den, other.num, self.den)
+    }
+}
+
+impl Ord for Price {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.cmp_value(other).then_with(|| self.num.cmp(&other.num))
+    }
+}
+
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// FIFO queue of order ids resting at one price.
+#[derive(BorshDeserialize, BorshSerialize, Clone, Default)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct PriceLevel {
+    pub head: Option<u64>,
+    pub tail: Option<u64>,
+    pub order_count: u64,
+    pub total_base: u128,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Clone, Default)]
+#[borsh(crate = "near_sdk::borsh")]
+struct QueueLink {
+    prev: Option<u64>,
+    next: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceLevelView {
+    pub price_num: U128,
+    pub price_den: U128,
+    pub total_base: U128,
+    pub order_count: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct DepthView {
+    pub bids: Vec<PriceLevelView>,
+    pub asks: Vec<PriceLevelView>,
+}
+
+/// Resting open orders: bids and asks keyed by price, each level a FIFO
+/// queue threaded through `links` so cancels can unlink in O(1).
+#[derive(BorshDeserialize, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct OrderBook {
+    bids: TreeMap<Price, PriceLevel>,
+    asks: TreeMap<Price, PriceLevel>,
+    links: LookupMap<u64, QueueLink>,
+}
+
+impl OrderBook {
+    pub fn new<S: IntoStorageKey>(bids: S, asks: S, links: S) -> Self {
+        Self {
+            bids: TreeMap::new(bids),
+            asks: TreeMap::new(asks),
+            links: LookupMap::new(links),
+        }
+    }
+
+    fn levels(&self, side: &Side) -> &TreeMap<Price, PriceLevel> {
+        match side { Side::Buy => &self.bids, Side::Sell => &self.asks }
+    }
+
+    fn levels_mut(&mut self, side: &Side) -> &mut TreeMap<Price, PriceLevel> {
+        match side { Side::Buy => &mut self.bids, Side::Sell => &mut self.asks }
+    }
+
+    /// Appends an order to the back of its price level.
+    pub fn insert(&mut self, order: &Order) {
+        let price = Price::of(order);
+        let mut level = self.levels(&order.side).get(&price).unwrap_or_default();
+        let link = QueueLink { prev: level.tail, next: None };
+        if let Some(tail) = level.tail {
+            let mut tail_link = self.links.get(&tail).expect("broken level queue");
+            tail_link.next = Some(order.id);
+            self.links.insert(&tail, &tail_link);
+        } else {
+            level.head = Some(order.id);
+        }
+        level.tail = Some(order.id);
+        level.order_count += 1;
+        level.total_base += order.remaining_base.0;
+        self.links.insert(&order.id, &link);
+        self.levels_mut(&order.side).insert(&price, &level);
+    }
+
+    /// Unlinks an order from its level, dropping the level once empty.
+    /// `order.remaining_base` is what gets taken off the level total.
+    pub fn remove(&mut self, order: &Order) {
+        let link = match self.links.remove(&order.id) {
+            Some(link) => link,
+            None => return,
+        };
+        let price = Price::of(order);
+        let mut level = self.levels(&order.side).get(&price).expect("level not found");
+        match link.prev {
+            Some(prev) => {
+                let mut prev_link = self.links.get(&prev).expect("broken level queue");
+                prev_link.next = link.next;
+                self.links.insert(&prev, &prev_link);
+            }
+            None => level.head = link.next,
+        }
+        match link.next {
+            Some(next) => {
+                let mut next_link = self.links.get(&next).expect("broken level queue");
+                next_link.prev = link.prev;
+                self.links.insert(&next, &next_link);
+            }
+            None => level.tail = link.prev,
+        }
+        level.order_count -= 1;
+        level.total_base -= order.remaining_base.0;
+        if level.order_count == 0 {
+            self.levels_mut(&order.side).remove(&price);
+        } else {
+            self.levels_mut(&order.side).insert(&price, &level);
+        }
+    }
+
+    /// Accounts for `base_fill` taken from a resting order; `order` is its
+    /// post-fill state and is unlinked once nothing remains.
+    pub fn fill(&mut self, order: &Order, base_fill: u128) {
+        if !self.links.contains_key(&order.id) {
+            return;
+        }
+        let price = Price::of(order);
+        let mut level = self.levels(&order.side).get(&price).expect("level not found");
+        level.total_base -= base_fill;
+        self.levels_mut(&order.side).insert(&price, &level);
+        if order.remaining_base.0 == 0 {
+            self.remove(order);
+        }
+    }
+
+    /// Best resting price on `side`: highest bid or lowest ask.
+    pub fn best(&self, side: &Side) -> Option<(Price, PriceLevel)> {
+        let price = match side { Side::Buy => self.bids.max(), Side::Sell => self.asks.min() }?;
+        let level = self.levels(side).get(&price)?;
+        Some((price, level))
+    }
+
+    /// Up to `limit` levels on `side`, best price first.
+    pub fn depth(&self, side: &Side, limit: usize) -> Vec<(Price, PriceLevel)> {
+        match side {
+            Side::Buy => self.bids.iter_rev().take(limit).collect(),
+            Side::Sell => self.asks.iter().take(limit).collect(),
+        }
+    }
+
+    /// Order ids queued at `price` on `side`, oldest first.
+    pub fn level_orders(&self, side: &Side, price: &Price, limit: usize) -> Vec<u64> {
+        let mut ids = vec![];
+        let mut cursor = self.levels(side).get(price).and_then(|l| l.head);
+        while let Some(id) = cursor {
+            if ids.len() >= limit {
+                break;
+            }
+            ids.push(id);
+            cursor = self.links.get(&id).and_then(|l| l.next);
+        }
+        ids
+    }
+}
+
+impl PriceLevelView {
+    pub fn new(price: &Price, level: &PriceLevel) -> Self {
+        Self {
+            price_num: U128(price.num),
+            price_den: U128(price.den),
+            total_base: U128(level.total_base),
+            order_count: level.order_count,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::json_types::U128;
+
+    use super::*;
+    use crate::test_utils::{acc, context};
+    use crate::{limit_request, TimeInForce};
+
+    fn book() -> OrderBook {
+        context("ob.near");
+        OrderBook::new(b"b".to_vec(), b"a".to_vec(), b"l".to_vec())
+    }
+
+    fn ask(id: u64, num: u128, amount_base: u128) -> Order {
+        let request = limit_request("sell", U128(amount_base), None, U128(num), U128(1), TimeInForce::Gtc);
+        Order::new(id, 0, acc("alice.near"), request)
+    }
+
+    fn ask_ids(book: &OrderBook, num: u128) -> Vec<u64> {
+        book.level_orders(&Side::Sell, &Price::new(num, 1), 10)
+    }
+
+    #[test]
+    fn remove_relinks_the_middle_head_and_tail() {
+        let mut book = book();
+        let orders: Vec<Order> = (0..5).map(|id| ask(id, 3, 10)).collect();
+        orders.iter().for_each(|order| book.insert(order));
+        book.remove(&orders[2]);
+        assert_eq!(ask_ids(&book, 3), vec![0, 1, 3, 4]);
+        book.remove(&orders[0]);
+        assert_eq!(ask_ids(&book, 3), vec![1, 3, 4]);
+        book.remove(&orders[4]);
+        assert_eq!(ask_ids(&book, 3), vec![1, 3]);
+        book.insert(&ask(5, 3, 10));
+        assert_eq!(ask_ids(&book, 3), vec![1, 3, 5]);
+        let (_, level) = book.best(&Side::Sell).unwrap();
+        assert_eq!((level.head, level.tail, level.order_count, level.total_base), (Some(1), Some(5), 3, 30));
+    }
+
+    #[test]
+    fn removing_the_last_order_drops_its_level() {
+        let mut book = book();
+        let cheap = ask(0, 2, 10);
+        book.insert(&cheap);
+        book.insert(&ask(1, 3, 10));
+        book.remove(&cheap);
+        book.remove(&cheap);
+        let (price, level) = book.best(&Side::Sell).unwrap();
+        assert_eq!((price.num, level.order_count), (3, 1));
+        assert!(ask_ids(&book, 2).is_empty());
+        assert!(book.best(&Side::Buy).is_none());
+    }
+
+    #[test]
+    fn fill_takes_from_the_level_and_unlinks_filled_orders() {
+        let mut book = book();
+        let mut order = ask(0, 3, 10);
+        book.insert(&order);
+        book.insert(&ask(1, 3, 10));
+        order.remaining_base = U128(6);
+        book.fill(&order, 4);
+        assert_eq!(book.best(&Side::Sell).unwrap().1.total_base, 16);
+        order.remaining_base = U128(0);
+        book.fill(&order, 6);
+        let (_, level) = book.best(&Side::Sell).unwrap();
+        assert_eq!((level.head, level.order_count, level.total_base), (Some(1), 1, 10));
+    }
+
+    #[test]
+    fn depth_reflects_cancels() {
+        let mut book = book();
+        let orders = [ask(0, 4, 10), ask(1, 2, 10), ask(2, 3, 5), ask(3, 3, 7)];
+        orders.iter().for_each(|order| book.insert(order));
+        book.remove(&orders[1]);
+        book.remove(&orders[2]);
+        let depth: Vec<_> = book
+            .depth(&Side::Sell, 10)
+            .iter()
+            .map(|(price, level)| (price.num, level.order_count, level.total_base))
+            .collect();
+        assert_eq!(depth, vec![(3, 1, 7), (4, 1, 10)]);
+    }
+}
@@ -0,0 +1,336 @@
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, near_bindgen, AccountId, Promise, PromiseError};
+
+use crate::matchers::BPS_DENOMINATOR;
+use crate::math::mul_div_floor;
+use crate::storage::balance_entry_bytes;
+use crate::{emit_event, ft_transfer, Contract, ContractExt, MarketId, Order, Role, TokenId, GAS_FOR_RESOLVE_WITHDRAW};
+
+/// Upper bound on either trading fee, and on the integrator fee cap: 1% of
+/// what a side receives.
+pub const MAX_FEE_BPS: u16 = 100;
+/// Upper bound on the number of volume tiers.
+pub const MAX_FEE_TIERS: usize = 10;
+/// Days of traded volume that count towards an account's fee tier.
+pub const VOLUME_WINDOW_DAYS: u64 = 30;
+const NANOS_PER_DAY: u64 = 86_400 * 1_000_000_000;
+
+/// Rates for accounts whose rolling quote volume is at least `min_volume`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeTier {
+    pub min_volume: U128,
+    pub maker_fee_bps: u16,
+    pub taker_fee_bps: u16,
+}
+
+/// Quote volume an account traded on one day (days since the Unix epoch).
+#[derive(BorshDeserialize, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct DailyVolume {
+    pub day: u64,
+    pub volume: u128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeConfigView {
+    pub maker_fee_bps: u16,
+    pub taker_fee_bps: u16,
+    pub tiers: Vec<FeeTier>,
+    pub max_integrator_fee_bps: u16,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct AccountFeeTierView {
+    /// Quote volume over the last `VOLUME_WINDOW_DAYS` days.
+    pub volume: U128,
+    /// Index into the tier table, `null` for the base rates.
+    pub tier: Option<u32>,
+    pub maker_fee_bps: u16,
+    pub taker_fee_bps: u16,
+}
+
+fn today() -> u64 {
+    env::block_timestamp() / NANOS_PER_DAY
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Owner or fee manager. Base rates, for accounts below every tier. Each
+    /// side of a fill pays its fee in the token it receives: the seller in
+    /// quote, the buyer in base.
+    #[payable]
+    pub fn set_fee_config(&mut self, maker_fee_bps: u16, taker_fee_bps: u16) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        assert!(maker_fee_bps <= MAX_FEE_BPS && taker_fee_bps <= MAX_FEE_BPS, "fee too high");
+        self.maker_fee_bps = maker_fee_bps;
+        self.taker_fee_bps = taker_fee_bps;
+        emit_event(
+            "fee_config",
+            near_sdk::serde_json::json!({
+                "maker_fee_bps": maker_fee_bps,
+                "taker_fee_bps": taker_fee_bps,
+                "by": env::predecessor_account_id(),
+            }),
+        );
+    }
+
+    /// Owner or fee manager. Replaces the tier table; tiers must be in
+    /// strictly increasing `min_volume` order. An empty table charges
+    /// everyone the base rates.
+    #[payable]
+    pub fn set_fee_tiers(&mut self, tiers: Vec<FeeTier>) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        assert!(tiers.len() <= MAX_FEE_TIERS, "too many fee tiers");
+        for tier in &tiers {
+            assert!(tier.maker_fee_bps <= MAX_FEE_BPS && tier.taker_fee_bps <= MAX_FEE_BPS, "fee too high");
+        }
+        assert!(tiers.windows(2).all(|w| w[0].min_volume.0 < w[1].min_volume.0), "tiers must increase in min_volume");
+        emit_event(
+            "fee_tiers",
+            near_sdk::serde_json::json!({
+                "tiers": tiers,
+                "by": env::predecessor_account_id(),
+            }),
+        );
+        self.fee_tiers = tiers;
+    }
+
+    /// Owner or fee manager. Highest `integrator_fee_bps` an order may
+    /// carry; 0 turns integrator fees off for new orders.
+    #[payable]
+    pub fn set_max_integrator_fee(&mut self, max_fee_bps: u16) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        assert!(max_fee_bps <= MAX_FEE_BPS, "fee too high");
+        self.max_integrator_fee_bps = max_fee_bps;
+        emit_event(
+            "integrator_fee_cap",
+            near_sdk::serde_json::json!({
+                "max_fee_bps": max_fee_bps,
+                "by": env::predecessor_account_id(),
+            }),
+        );
+    }
+
+    pub fn get_fee_config(&self) -> FeeConfigView {
+        FeeConfigView {
+            maker_fee_bps: self.maker_fee_bps,
+            taker_fee_bps: self.taker_fee_bps,
+            tiers: self.fee_tiers.clone(),
+            max_integrator_fee_bps: self.max_integrator_fee_bps,
+        }
+    }
+
+    /// Rates `account_id` pays in `market_id` given its rolling volume in
+    /// the market's quote token.
+    pub fn get_account_fee_tier(&self, account_id: AccountId, market_id: MarketId) -> AccountFeeTierView {
+        let volume = self.rolling_volume(&account_id, &self.market(market_id).quote_token_id);
+        let (tier, maker_fee_bps, taker_fee_bps) = self.fee_rates(volume);
+        AccountFeeTierView { volume: U128(volume), tier, maker_fee_bps, taker_fee_bps }
+    }
+
+    /// Fees collected and not yet withdrawn, per token.
+    pub fn get_treasury_balances(&self) -> Vec<(TokenId, U128)> {
+        self.treasury.iter().map(|(token_id, amount)| (token_id, U128(amount))).collect()
+    }
+
+    /// Sends `amount` (all by default) of the fees collected in `token_id` to
+    /// `receiver_id` (the caller by default). Owner or fee manager.
+    #[payable]
+    pub fn withdraw_treasury(&mut self, token_id: TokenId, amount: Option<U128>, receiver_id: Option<AccountId>) -> Promise {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        let available = self.treasury.get(&token_id).unwrap_or(0);
+        let amount = amount.map_or(available, |a| a.0);
+        assert!(amount > 0, "amount must be > 0");
+        assert!(amount <= available, "amount exceeds treasury balance");
+        self.internal_sub_treasury(&token_id, amount);
+        let receiver_id = receiver_id.unwrap_or_else(env::predecessor_account_id);
+        emit_event(
+            "treasury_withdraw",
+            near_sdk::serde_json::json!({
+                "token_id": token_id,
+                "amount": U128(amount),
+                "receiver_id": receiver_id,
+                "by": env::predecessor_account_id(),
+            }),
+        );
+        ft_transfer(&token_id, &receiver_id, amount).then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_RESOLVE_WITHDRAW)
+                .resolve_treasury_withdraw(token_id, U128(amount)),
+        )
+    }
+
+    /// Puts a failed `withdraw_treasury` transfer back into the treasury.
+    /// Returns the amount actually withdrawn.
+    #[private]
+    pub fn resolve_treasury_withdraw(&mut self, token_id: TokenId, amount: U128) -> U128 {
+        // `ft_transfer` returns nothing; only a failed call is put back.
+        if !matches!(env::promise_result_checked(0, 0), Err(PromiseError::Failed)) {
+            return amount;
+        }
+        self.internal_add_treasury(&token_id, amount.0);
+        emit_event(
+            "treasury_withdraw_failed",
+            near_sdk::serde_json::json!({
+                "token_id": token_id,
+                "amount": amount,
+            }),
+        );
+        U128(0)
+    }
+}
+
+impl Contract {
+    /// Fee `account_id` owes on `received` as the maker or taker side of a
+    /// fill, at the rates of its volume tier in `quote_token_id`.
+    pub(crate) fn trading_fee(&self, account_id: &AccountId, quote_token_id: &TokenId, received: u128, is_maker: bool) -> u128 {
+        let (_, maker_fee_bps, taker_fee_bps) = self.fee_rates(self.rolling_volume(account_id, quote_token_id));
+        let bps = if is_maker { maker_fee_bps } else { taker_fee_bps };
+        mul_div_floor(received, bps as u128, BPS_DENOMINATOR)
+    }
+
+    /// Validates the integrator an order is placed through. A zero or
+    /// missing fee leaves the order without one.
+    pub(crate) fn checked_integrator(&self, integrator_id: Option<AccountId>, fee_bps: Option<u16>) -> (Option<AccountId>, u16) {
+        let fee_bps = fee_bps.unwrap_or(0);
+        if fee_bps == 0 {
+            return (None, 0);
+        }
+        let integrator_id = integrator_id.expect("integrator_id required with integrator_fee_bps");
+        assert!(fee_bps <= self.max_integrator_fee_bps, "integrator fee too high");
+        assert!(self.is_registered(&integrator_id), "integrator is not registered");
+        (Some(integrator_id), fee_bps)
+    }
+
+    /// Integrator fee on `received` (in `token_id`) by `order`'s owner in a
+    /// fill; nothing once the integrator has unregistered, or while its
+    /// storage deposit doesn't cover a new balance entry in `token_id`.
+    pub(crate) fn integrator_fee(&self, order: &Order, token_id: &TokenId, received: u128) -> u128 {
+        let Some(integrator_id) = &order.integrator_id else {
+            return 0;
+        };
+        if !self.is_registered(integrator_id) {
+            return 0;
+        }
+        if self.internal_get_balance(integrator_id, token_id) == 0
+            && !self.can_store(integrator_id, balance_entry_bytes(integrator_id, token_id))
+        {
+            return 0;
+        }
+        mul_div_floor(received, order.integrator_fee_bps as u128, BPS_DENOMINATOR)
+    }
+
+    /// Highest tier `volume` reaches, with its rates, or the base rates.
+    fn fee_rates(&self, volume: u128) -> (Option<u32>, u16, u16) {
+        match self.fee_tiers.iter().rposition(|tier| volume >= tier.min_volume.0) {
+            Some(index) => {
+                let tier = &self.fee_tiers[index];
+                (Some(index as u32), tier.maker_fee_bps, tier.taker_fee_bps)
+            }
+            None => (None, self.maker_fee_bps, self.taker_fee_bps),
+        }
+    }
+
+    fn rolling_volume(&self, account_id: &AccountId, quote_token_id: &TokenId) -> u128 {
+        let since = today().saturating_sub(VOLUME_WINDOW_DAYS - 1);
+        let key = (account_id.clone(), quote_token_id.clone());
+        let days = self.account_volumes.get(&key).unwrap_or_default();
+        days.iter().filter(|d| d.day >= since).map(|d| d.volume).sum()
+    }
+
+    /// Adds `volume` to today's bucket of a registered account, dropping
+    /// days that left the window. Unregistered accounts (the swap escrow,
+    /// migrated balances) are not tracked and pay the base rates. The bytes
+    /// the history grows or shrinks by are charged to or released from the
+    /// account, even past its deposit, as it can't refuse the fill that
+    /// records them.
+    pub(crate) fn internal_record_volume(&mut self, account_id: &AccountId, quote_token_id: &TokenId, volume: u128) {
+        if !self.is_registered(account_id) {
+            return;
+        }
+        let today = today();
+        let since = today.saturating_sub(VOLUME_WINDOW_DAYS - 1);
+        let key = (account_id.clone(), quote_token_id.clone());
+        let mut days = self.account_volumes.get(&key).unwrap_or_default();
+        days.retain(|d| d.day >= since);
+        match days.last_mut() {
+            Some(last) if last.day == today => last.volume += volume,
+            _ => days.push(DailyVolume { day: today, volume }),
+        }
+        let initial_storage = env::storage_usage();
+        self.account_volumes.insert(&key, &days);
+        let final_storage = env::storage_usage();
+        if final_storage > initial_storage {
+            self.internal_storage_force_charge(account_id, final_storage - initial_storage);
+        } else {
+            self.internal_storage_release(account_id, initial_storage - final_storage);
+        }
+    }
+
+    pub(crate) fn internal_add_treasury(&mut self, token_id: &TokenId, amount: u128) {
+        if amount == 0 {
+            return;
+        }
+        let current = self.treasury.get(token_id).unwrap_or(0);
+        self.treasury.insert(token_id, &(current + amount));
+    }
+
+    fn internal_sub_treasury(&mut self, token_id: &TokenId, amount: u128) {
+        let remaining = self.treasury.get(token_id).unwrap_or(0) - amount;
+        if remaining == 0 {
+            self.treasury.remove(token_id);
+        } else {
+            self.treasury.insert(token_id, &remaining);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_contract_standards::storage_management::StorageManagement;
+
+    use super::*;
+    use crate::test_utils::{acc, alice_sells_to_bob, context, new_market, register, register_with_deposit};
+
+    /// Alice sells 1000 base at 1 to bob through carol's front-end, which
+    /// takes 50 bps of the quote alice receives.
+    fn integrated_trade(contract: &mut Contract, market_id: MarketId) {
+        context("owner.near");
+        contract.set_max_integrator_fee(100);
+        register(contract, "alice.near");
+        register(contract, "bob.near");
+        alice_sells_to_bob(contract, market_id, Some(acc("carol.near")), Some(50));
+    }
+
+    #[test]
+    fn integrator_credit_is_charged_to_the_integrator() {
+        let (mut contract, market_id) = new_market();
+        register(&mut contract, "carol.near");
+        let registered = contract.storage_accounts.get(&acc("carol.near")).unwrap().used_bytes;
+        integrated_trade(&mut contract, market_id);
+        assert_eq!(contract.get_balance(acc("carol.near"), acc("quote.near")).0, 5);
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("quote.near")).0, 995);
+        let charged = balance_entry_bytes(&acc("carol.near"), &acc("quote.near"));
+        assert_eq!(contract.storage_accounts.get(&acc("carol.near")).unwrap().used_bytes, registered + charged);
+    }
+
+    #[test]
+    fn integrator_without_storage_takes_no_fee() {
+        let (mut contract, market_id) = new_market();
+        let min = contract.storage_balance_bounds().min;
+        register_with_deposit(&mut contract, "carol.near", min);
+        integrated_trade(&mut contract, market_id);
+        assert_eq!(contract.get_balance(acc("carol.near"), acc("quote.near")).0, 0);
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("quote.near")).0, 1_000);
+    }
+}
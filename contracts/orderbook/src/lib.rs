@@ -1,14 +1,46 @@
 use near_sdk::{
     assert_one_yocto, env, near_bindgen, AccountId, BorshStorageKey, PanicOnDefault,
-    Promise, PromiseOrValue, Gas, NearToken,
+    Promise, PromiseError, Gas, NearToken,
 };
 use near_contract_standards::fungible_token::Balance;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
 use near_sdk::serde::{Deserialize, Serialize};
-use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
 use near_sdk::json_types::U128;
 
+mod admin;
+mod book;
+mod fees;
+mod markets;
+mod math;
+mod matchers;
+mod matching;
+mod referrals;
+mod roles;
+mod self_trade;
+mod stops;
+mod storage;
+mod token_receiver;
+mod upgrade;
+#[cfg(test)]
+mod test_utils;
+
+use math::checked_mul_div_floor;
+use self_trade::{parse_self_trade_prevention, self_trade_mode, self_trade_prevention_str};
+
+pub use admin::ContractStatus;
+pub use book::{DepthView, OrderBook, Price, PriceLevelView};
+pub use fees::{AccountFeeTierView, DailyVolume, FeeConfigView, FeeTier, MAX_FEE_BPS, MAX_FEE_TIERS, VOLUME_WINDOW_DAYS};
+pub use markets::{Market, MarketId, MarketView};
+pub use matchers::{MatcherConfigView, MatcherMode, MatcherStats, MAX_KEEPER_REWARD_BPS};
+pub use matching::DEFAULT_MAX_MATCHES;
+pub use referrals::MAX_REFERRAL_SHARE_BPS;
+pub use roles::Role;
+pub use self_trade::SelfTradePrevention;
+pub use stops::{StopBook, DEFAULT_MAX_TRIGGERS};
+pub use storage::StorageAccount;
+pub use upgrade::STATE_VERSION;
+
 pub type TokenId = AccountId;
 
 #[derive(BorshSerialize, BorshStorageKey)]
@@ -18,6 +50,24 @@ enum StorageKey {
     Orders,
     OrdersByOwner,
     OrdersByOwnerSet { account_hash: Vec<u8> },
+    StorageAccounts,
+    ClosedOrders,
+    RoleMembers,
+    RoleMembersSet { role: Role },
+    MatcherStats,
+    Markets,
+    ListedTokens,
+    MarketBids { market_id: MarketId },
+    MarketAsks { market_id: MarketId },
+    MarketBookLinks { market_id: MarketId },
+    MarketStopBuys { market_id: MarketId },
+    MarketStopSells { market_id: MarketId },
+    Treasury,
+    AccountVolumes,
+    Referrers,
+    Referees,
+    RefereesSet { account_hash: Vec<u8> },
+    ReferralEarnings,
 }
 
 #[derive(BorshSerialize, BorshDeserialize)]
@@ -34,18 +84,50 @@ pub enum Side {
     Sell,
 }
 
+impl Side {
+    pub fn opposite(&self) -> Side {
+        match self { Side::Buy => Side::Sell, Side::Sell => Side::Buy }
+    }
+}
+
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, PartialEq, Debug)]
 #[serde(crate = "near_sdk::serde")]
 pub enum OrderStatus {
     Open,
     Filled,
     Cancelled,
+    /// Stop order waiting for its trigger price; funds are already locked.
+    Pending,
+    Expired,
+}
+
+/// How long an order may stay on the book after its initial sweep.
+/// `PostOnly` is rejected if it would cross; `PostOnlySlide` is re-priced one
+/// step (at its own denominator) inside the best opposite price instead.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum TimeInForce {
+    Gtc,
+    Ioc,
+    Fok,
+    PostOnly,
+    PostOnlySlide,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum OrderType {
+    Limit,
+    /// Fills immediately up to a worst price and never rests. Buys are
+    /// sized by their quote lock and keep `amount_base`/`remaining_base` at 0.
+    Market,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct Order {
     pub id: u64,
+    pub market_id: MarketId,
     pub owner_id: AccountId,
     pub side: Side,
     pub price_num: U128, // quote per unit base (numerator)
@@ -56,6 +138,261 @@ pub struct Order {
     pub locked_base_remaining: U128,  // for Sell orders
     pub status: OrderStatus,
     pub created_at: u64,
+    pub time_in_force: TimeInForce,
+    pub order_type: OrderType,
+    /// Last trade price that activates a stop order.
+    pub trigger_price: Option<Price>,
+    /// Millisecond timestamp (like `created_at`) after which the order can
+    /// no longer trade and may be expired by anyone.
+    pub expires_at: Option<u64>,
+    /// Front-end credited `integrator_fee_bps` of what the owner receives
+    /// in each fill.
+    pub integrator_id: Option<AccountId>,
+    pub integrator_fee_bps: u16,
+    /// Overrides the market's self-trade prevention when this order takes.
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+    /// Whether the owner's storage deposit paid for this order; not for
+    /// orders migrated from before storage management.
+    pub storage_charged: bool,
+}
+
+/// What stays of an order once it is filled, cancelled or expired and
+/// removed from `orders`; the full final state is only in the events.
+#[derive(BorshDeserialize, BorshSerialize, Clone)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct ClosedOrder {
+    pub id: u64,
+    pub side: Side,
+    pub status: OrderStatus,
+    pub price: Price,
+    pub closed_at: u64,
+}
+
+/// Closed orders kept per account, newest first.
+pub const MAX_CLOSED_ORDERS: usize = 10;
+
+/// A validated order request, before any funds are locked.
+pub(crate) struct NewOrder {
+    pub side: Side,
+    pub order_type: OrderType,
+    pub time_in_force: TimeInForce,
+    pub price: Price,
+    /// Base to trade; 0 for quote-sized market buys.
+    pub amount_base: u128,
+    /// Quote locked by a buy.
+    pub max_spend_quote: u128,
+    pub trigger_price: Option<Price>,
+    pub expires_at: Option<u64>,
+    pub integrator_id: Option<AccountId>,
+    pub integrator_fee_bps: u16,
+    pub self_trade_prevention: Option<SelfTradePrevention>,
+}
+
+fn ft_transfer(token_id: &TokenId, receiver_id: &AccountId, amount: u128) -> Promise {
+    let memo: Option<String> = None;
+    Promise::new(token_id.clone()).function_call(
+        "ft_transfer".to_string(),
+        near_sdk::serde_json::to_vec(&near_sdk::serde_json::json!({
+            "receiver_id": receiver_id,
+            "amount": U128(amount),
+            "memo": memo,
+        })).unwrap(),
+        NearToken::from_yoctonear(1),
+        Gas::from_tgas(10),
+    )
+}
+
+fn now_ms() -> u64 {
+    env::block_timestamp() / 1_000_000
+}
+
+fn limit_request(
+    side: &str,
+    amount_base: U128,
+    max_spend_quote: Option<U128>,
+    price_num: U128,
+    price_den: U128,
+    time_in_force: TimeInForce,
+) -> NewOrder {
+    assert!(amount_base.0 > 0, "amount_base must be > 0");
+    assert!(price_num.0 > 0 && price_den.0 > 0, "price must be positive");
+    let side = parse_side(side);
+    let max_spend_quote = match side {
+        Side::Buy => {
+            let spend = max_spend_quote.expect("max_spend_quote required for Buy").0;
+            assert!(spend > 0, "max_spend_quote must be > 0");
+            spend
+        }
+        Side::Sell => 0,
+    };
+    NewOrder {
+        side,
+        order_type: OrderType::Limit,
+        time_in_force,
+        price: Price::new(price_num.0, price_den.0),
+        amount_base: amount_base.0,
+        max_spend_quote,
+        trigger_price: None,
+        expires_at: None,
+        integrator_id: None,
+        integrator_fee_bps: 0,
+        self_trade_prevention: None,
+    }
+}
+
+fn checked_expiry(expires_at: u64) -> u64 {
+    assert!(expires_at > now_ms(), "expires_at must be in the future");
+    expires_at
+}
+
+fn market_request(side: &str, amount: U128, worst_price_num: U128, worst_price_den: U128) -> NewOrder {
+    assert!(amount.0 > 0, "amount must be > 0");
+    assert!(worst_price_num.0 > 0 && worst_price_den.0 > 0, "price must be positive");
+    let side = parse_side(side);
+    let (amount_base, max_spend_quote) = match side {
+        Side::Buy => (0, amount.0),
+        Side::Sell => (amount.0, 0),
+    };
+    NewOrder {
+        side,
+        order_type: OrderType::Market,
+        time_in_force: TimeInForce::Ioc,
+        price: Price::new(worst_price_num.0, worst_price_den.0),
+        amount_base,
+        max_spend_quote,
+        trigger_price: None,
+        expires_at: None,
+        integrator_id: None,
+        integrator_fee_bps: 0,
+        self_trade_prevention: None,
+    }
+}
+
+impl Order {
+    /// An order with id `id`, holding the funds `request` locks.
+    pub(crate) fn new(id: u64, market_id: MarketId, owner_id: AccountId, request: NewOrder) -> Self {
+        let (locked_quote, locked_base) = match request.side {
+            Side::Buy => (request.max_spend_quote, 0),
+            Side::Sell => (0, request.amount_base),
+        };
+        Order {
+            id,
+            market_id,
+            owner_id,
+            side: request.side,
+            price_num: U128(request.price.num),
+            price_den: U128(request.price.den),
+            amount_base: U128(request.amount_base),
+            remaining_base: U128(request.amount_base),
+            locked_quote_remaining: U128(locked_quote),
+            locked_base_remaining: U128(locked_base),
+            status: if request.trigger_price.is_some() { OrderStatus::Pending } else { OrderStatus::Open },
+            created_at: now_ms(),
+            time_in_force: request.time_in_force,
+            order_type: request.order_type,
+            trigger_price: request.trigger_price,
+            expires_at: request.expires_at,
+            integrator_id: request.integrator_id,
+            integrator_fee_bps: request.integrator_fee_bps,
+            self_trade_prevention: request.self_trade_prevention,
+            storage_charged: true,
+        }
+    }
+
+    pub fn is_expired(&self, now_ms: u64) -> bool {
+        self.expires_at.is_some_and(|t| now_ms >= t)
+    }
+
+    /// Market buys spend a quote budget instead of targeting a base amount.
+    pub fn is_quote_sized(&self) -> bool {
+        self.order_type == OrderType::Market && self.side == Side::Buy
+    }
+
+    /// Base the order can still take; a quote-sized order is done once its
+    /// lock no longer buys one unit at its worst price.
+    pub fn base_capacity(&self) -> u128 {
+        if self.is_quote_sized() {
+            checked_mul_div_floor(self.locked_quote_remaining.0, self.price_den.0, self.price_num.0).unwrap_or(u128::MAX)
+        } else {
+            self.remaining_base.0
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct OrderView {
+    pub id: u64,
+    pub market_id: MarketId,
+    pub owner_id: AccountId,
+    pub side: String,
+    pub price_num: U128,
+    pub price_den: U128,
+    pub amount_base: U128,
+    pub remaining_base: U128,
+    pub locked_quote_remaining: U128,
+    pub locked_base_remaining: U128,
+    pub status: String,
+    pub created_at: u64,
+    pub time_in_force: String,
+    pub order_type: String,
+    pub trigger_price_num: Option<U128>,
+    pub trigger_price_den: Option<U128>,
+    pub expires_at: Option<u64>,
+    pub integrator_id: Option<AccountId>,
+    pub integrator_fee_bps: u16,
+    pub self_trade_prevention: Option<String>,
+}
+
+impl From<Order> for OrderView {
+    fn from(o: Order) -> Self {
+        Self {
+            id: o.id,
+            market_id: o.market_id,
+            owner_id: o.owner_id,
+            side: side_str(&o.side).to_string(),
+            price_num: o.price_num,
+            price_den: o.price_den,
+            amount_base: o.amount_base,
+            remaining_base: o.remaining_base,
+            locked_quote_remaining: o.locked_quote_remaining,
+            locked_base_remaining: o.locked_base_remaining,
+            status: status_str(&o.status).to_string(),
+            created_at: o.created_at,
+            time_in_force: tif_str(&o.time_in_force).to_string(),
+            order_type: order_type_str(&o.order_type).to_string(),
+            trigger_price_num: o.trigger_price.map(|p| U128(p.num)),
+            trigger_price_den: o.trigger_price.map(|p| U128(p.den)),
+            expires_at: o.expires_at,
+            integrator_id: o.integrator_id,
+            integrator_fee_bps: o.integrator_fee_bps,
+            self_trade_prevention: o.self_trade_prevention.map(|m| self_trade_prevention_str(m).to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ClosedOrderView {
+    pub id: u64,
+    pub side: String,
+    pub status: String,
+    pub price_num: U128,
+    pub price_den: U128,
+    pub closed_at: u64,
+}
+
+impl From<ClosedOrder> for ClosedOrderView {
+    fn from(o: ClosedOrder) -> Self {
+        Self {
+            id: o.id,
+            side: side_str(&o.side).to_string(),
+            status: status_str(&o.status).to_string(),
+            price_num: U128(o.price.num),
+            price_den: U128(o.price.den),
+            closed_at: o.closed_at,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -67,6 +404,8 @@ struct OBEvent<T> {
     data: T,
 }
 
+const GAS_FOR_RESOLVE_WITHDRAW: Gas = Gas::from_tgas(10);
+
 const EVENT_STANDARD: &str = "orderbook";
 const EVENT_VERSION: &str = "1.0.0";
 
@@ -88,113 +427,173 @@ fn side_str(side: &Side) -> &'static str {
 }
 
 fn status_str(st: &OrderStatus) -> &'static str {
-    match st { OrderStatus::Open => "open", OrderStatus::Filled => "filled", OrderStatus::Cancelled => "cancelled" }
+    match st {
+        OrderStatus::Open => "open",
+        OrderStatus::Filled => "filled",
+        OrderStatus::Cancelled => "cancelled",
+        OrderStatus::Pending => "pending",
+        OrderStatus::Expired => "expired",
+    }
+}
+
+fn parse_time_in_force(s: &str) -> TimeInForce {
+    match s.to_ascii_lowercase().as_str() {
+        "gtc" => TimeInForce::Gtc,
+        "ioc" => TimeInForce::Ioc,
+        "fok" => TimeInForce::Fok,
+        "post_only" => TimeInForce::PostOnly,
+        "post_only_slide" => TimeInForce::PostOnlySlide,
+        _ => env::panic_str("invalid time_in_force"),
+    }
+}
+
+fn order_type_str(t: &OrderType) -> &'static str {
+    match t { OrderType::Limit => "limit", OrderType::Market => "market" }
+}
+
+fn tif_str(tif: &TimeInForce) -> &'static str {
+    match tif {
+        TimeInForce::Gtc => "gtc",
+        TimeInForce::Ioc => "ioc",
+        TimeInForce::Fok => "fok",
+        TimeInForce::PostOnly => "post_only",
+        TimeInForce::PostOnlySlide => "post_only_slide",
+    }
 }
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Contract {
-    pub base_token_id: TokenId,
-    pub quote_token_id: TokenId,
+    owner_id: AccountId,
+    status: ContractStatus,
+    role_members: LookupMap<Role, UnorderedSet<AccountId>>,
+    matcher_mode: MatcherMode,
+    keeper_reward_bps: u16,
+    matcher_stats: LookupMap<AccountId, MatcherStats>,
+    maker_fee_bps: u16,
+    taker_fee_bps: u16,
+    /// Trading fees collected per token, withdrawn by the fee manager.
+    treasury: UnorderedMap<TokenId, Balance>,
+    /// Volume tiers by increasing `min_volume`, overriding the base rates.
+    fee_tiers: Vec<FeeTier>,
+    /// Daily quote volume per (account, quote token) over the tier window.
+    account_volumes: LookupMap<(AccountId, TokenId), Vec<DailyVolume>>,
+    /// Cap on the integrator fee an order may carry.
+    max_integrator_fee_bps: u16,
+    /// Share of each fee a referee pays that goes to its referrer.
+    referral_share_bps: u16,
+    /// Referee -> referrer, set once.
+    referrers: LookupMap<AccountId, AccountId>,
+    referees: LookupMap<AccountId, UnorderedSet<AccountId>>,
+    /// Total referral credits per (referrer, token).
+    referral_earnings: LookupMap<(AccountId, TokenId), Balance>,
+
+    markets: UnorderedMap<MarketId, Market>,
+    next_market_id: MarketId,
+    /// Tokens of every listed market, accepted as deposits.
+    listed_tokens: UnorderedSet<TokenId>,
 
     balances: LookupMap<Vec<u8>, Balance>, // key = borsh(BalanceKey)
 
     orders: UnorderedMap<u64, Order>,
     orders_by_owner: LookupMap<AccountId, UnorderedSet<u64>>,
+    closed_orders: LookupMap<AccountId, Vec<ClosedOrder>>,
 
     next_order_id: u64,
+
+    storage_accounts: LookupMap<AccountId, StorageAccount>,
 }
 
 #[near_bindgen]
 impl Contract {
     #[init]
-    pub fn new(base_token_id: TokenId, quote_token_id: TokenId) -> Self {
+    /// Starts with no markets; list them with `add_market`.
+    pub fn new(owner_id: AccountId) -> Self {
         assert!(!env::state_exists(), "Already initialized");
+        upgrade::write_state_version();
         Self {
-            base_token_id,
-            quote_token_id,
+            owner_id,
+            status: ContractStatus::Running,
+            role_members: LookupMap::new(StorageKey::RoleMembers),
+            matcher_mode: MatcherMode::Whitelist,
+            keeper_reward_bps: 0,
+            matcher_stats: LookupMap::new(StorageKey::MatcherStats),
+            maker_fee_bps: 0,
+            taker_fee_bps: 0,
+            treasury: UnorderedMap::new(StorageKey::Treasury),
+            fee_tiers: vec![],
+            account_volumes: LookupMap::new(StorageKey::AccountVolumes),
+            max_integrator_fee_bps: 0,
+            referral_share_bps: 0,
+            referrers: LookupMap::new(StorageKey::Referrers),
+            referees: LookupMap::new(StorageKey::Referees),
+            referral_earnings: LookupMap::new(StorageKey::ReferralEarnings),
+            markets: UnorderedMap::new(StorageKey::Markets),
+            next_market_id: 0,
+            listed_tokens: UnorderedSet::new(StorageKey::ListedTokens),
             balances: LookupMap::new(StorageKey::Balances),
             orders: UnorderedMap::new(StorageKey::Orders),
             orders_by_owner: LookupMap::new(StorageKey::OrdersByOwner),
+            closed_orders: LookupMap::new(StorageKey::ClosedOrders),
             next_order_id: 0,
+            storage_accounts: LookupMap::new(StorageKey::StorageAccounts),
         }
     }
 
     #[payable]
+    #[allow(clippy::too_many_arguments)]
     pub fn place_order(
         &mut self,
+        market_id: MarketId,
         side: String,
         amount_base: U128,
         max_spend_quote: Option<U128>,
         price_num: U128,
         price_den: U128,
+        max_matches: Option<u32>,
+        time_in_force: Option<String>,
+        expires_at: Option<u64>,
+        integrator_id: Option<AccountId>,
+        integrator_fee_bps: Option<u16>,
+        self_trade_prevention: Option<String>,
     ) -> u64 {
         assert_one_yocto();
+        self.assert_running();
         let caller = env::predecessor_account_id();
-        let amount_base_u128: u128 = amount_base.0;
-        assert!(amount_base_u128 > 0, "amount_base must be > 0");
-        assert!(price_num.0 > 0 && price_den.0 > 0, "price must be positive");
-        let side_enum = parse_side(&side);
-
-        match side_enum {
-            Side::Buy => {
-                let spend = max_spend_quote.expect("max_spend_quote required for Buy").0;
-                assert!(spend > 0, "max_spend_quote must be > 0");
-                let quote_id = self.quote_token_id.clone();
-                let bal = self.internal_get_balance(&caller, &quote_id);
-                assert!(bal >= spend, "Insufficient quote balance");
-                self.internal_sub_balance(&caller, &quote_id, spend);
-                let order_id = self.internal_create_order(
-                    caller.clone(),
-                    Side::Buy,
-                    amount_base_u128,
-                    spend,
-                    0,
-                    price_num.0,
-                    price_den.0,
-                );
-                emit_event(
-                    "order_place",
-                    near_sdk::serde_json::json!({
-                        "order_id": order_id,
-                        "owner_id": caller,
-                        "side": "buy",
-                        "amount_base": amount_base_u128.to_string(),
-                        "max_spend_quote": spend.to_string(),
-                        "price_num": price_num.0.to_string(),
-                        "price_den": price_den.0.to_string(),
-                    }),
-                );
-                order_id
-            }
-            Side::Sell => {
-                let base_id = self.base_token_id.clone();
-                let bal = self.internal_get_balance(&caller, &base_id);
-                assert!(bal >= amount_base_u128, "Insufficient base balance");
-                self.internal_sub_balance(&caller, &base_id, amount_base_u128);
-                let order_id = self.internal_create_order(
-                    caller.clone(),
-                    Side::Sell,
-                    amount_base_u128,
-                    0,
-                    amount_base_u128,
-                    price_num.0,
-                    price_den.0,
-                );
-                emit_event(
-                    "order_place",
-                    near_sdk::serde_json::json!({
-                        "order_id": order_id,
-                        "owner_id": caller,
-                        "side": "sell",
-                        "amount_base": amount_base_u128.to_string(),
-                        "price_num": price_num.0.to_string(),
-                        "price_den": price_den.0.to_string(),
-                    }),
-                );
-                order_id
-            }
-        }
+        let tif = time_in_force.map_or(TimeInForce::Gtc, |t| parse_time_in_force(&t));
+        let mut request = limit_request(&side, amount_base, max_spend_quote, price_num, price_den, tif);
+        request.expires_at = expires_at.map(checked_expiry);
+        (request.integrator_id, request.integrator_fee_bps) = self.checked_integrator(integrator_id, integrator_fee_bps);
+        request.self_trade_prevention = self_trade_prevention.map(|m| parse_self_trade_prevention(&m));
+        let mut market = self.market(market_id);
+        let order_id = self.internal_place_order(&mut market, caller, request, max_matches);
+        self.save_market(&market);
+        order_id
+    }
+
+    /// Buys spend up to `amount` quote, sells sell up to `amount` base, never
+    /// trading beyond the worst price. Nothing rests: whatever is not filled
+    /// within `max_matches` goes back to the caller's balance.
+    #[payable]
+    pub fn place_market_order(
+        &mut self,
+        market_id: MarketId,
+        side: String,
+        amount: U128,
+        worst_price_num: U128,
+        worst_price_den: U128,
+        max_matches: Option<u32>,
+    ) -> u64 {
+        assert_one_yocto();
+        self.assert_running();
+        let caller = env::predecessor_account_id();
+        let request = market_request(&side, amount, worst_price_num, worst_price_den);
+        let mut market = self.market(market_id);
+        let mut order = self.internal_create_order(&market, caller, request);
+
+        self.internal_match_order(&mut market, &mut order, max_matches.unwrap_or(DEFAULT_MAX_MATCHES));
+        self.save_market(&market);
+        order.id
     }
 
     #[payable]
@@ -203,38 +602,38 @@ impl Contract {
         let caller = env::predecessor_account_id();
         let mut order = self.orders.get(&order_id).expect("Order not found");
         assert_eq!(order.owner_id, caller, "Only owner can cancel");
-        assert_eq!(order.status, OrderStatus::Open, "Order not open");
-
-        match order.side {
-            Side::Buy => {
-                let refund = order.locked_quote_remaining.0;
-                if refund > 0 {
-                    let quote_id = self.quote_token_id.clone();
-                    self.internal_add_balance(&caller, &quote_id, refund);
-                }
-            }
-            Side::Sell => {
-                let refund = order.locked_base_remaining.0;
-                if refund > 0 {
-                    let base_id = self.base_token_id.clone();
-                    self.internal_add_balance(&caller, &base_id, refund);
-                }
+        assert!(
+            order.status == OrderStatus::Open || order.status == OrderStatus::Pending,
+            "Order not open"
+        );
+        let mut market = self.market(order.market_id);
+        self.internal_cancel(&mut market, &mut order, "user");
+        self.save_market(&market);
+        self.internal_save_order(&order);
+    }
+
+    /// Permissionless keeper entry point: expires the listed orders whose
+    /// `expires_at` has passed, releasing their locks to the owners. Ids that
+    /// are unknown, closed or not yet due are skipped. Returns expired ids.
+    pub fn expire_orders(&mut self, order_ids: Vec<u64>) -> Vec<u64> {
+        let now = now_ms();
+        let mut expired = vec![];
+        for order_id in order_ids {
+            let mut order = match self.orders.get(&order_id) {
+                Some(order) => order,
+                None => continue,
+            };
+            let live = order.status == OrderStatus::Open || order.status == OrderStatus::Pending;
+            if !live || !order.is_expired(now) {
+                continue;
             }
+            let mut market = self.market(order.market_id);
+            self.internal_expire(&mut market, &mut order);
+            self.save_market(&market);
+            self.internal_save_order(&order);
+            expired.push(order_id);
         }
-
-        order.status = OrderStatus::Cancelled;
-        order.locked_base_remaining = U128(0);
-        order.locked_quote_remaining = U128(0);
-        order.remaining_base = U128(0);
-        self.orders.insert(&order_id, &order);
-
-        emit_event(
-            "order_cancel",
-            near_sdk::serde_json::json!({
-                "order_id": order_id,
-                "owner_id": caller,
-            }),
-        );
+        expired
     }
 
     #[payable]
@@ -246,6 +645,8 @@ impl Contract {
         quote_paid: U128,
     ) {
         assert_one_yocto();
+        self.assert_running();
+        let keeper_id = self.internal_check_matcher();
         assert!(maker_order_id != taker_order_id, "distinct orders required");
         let base_fill_u = base_fill.0;
         let quote_paid_u = quote_paid.0;
@@ -255,89 +656,37 @@ impl Contract {
         let mut taker = self.orders.get(&taker_order_id).expect("taker not found");
         assert_eq!(maker.status, OrderStatus::Open, "maker not open");
         assert_eq!(taker.status, OrderStatus::Open, "taker not open");
+        let now = now_ms();
+        assert!(!maker.is_expired(now), "maker expired");
+        assert!(!taker.is_expired(now), "taker expired");
 
         // Determine direction: they must be opposite sides
         assert!(maker.side != taker.side, "sides must be opposite");
+        assert_eq!(maker.market_id, taker.market_id, "orders are in different markets");
 
-        // Enforce price limits for both maker and taker
-        let (maker_num, maker_den) = (maker.price_num.0, maker.price_den.0);
-        let (taker_num, taker_den) = (taker.price_num.0, taker.price_den.0);
-
-        match maker.side {
-            Side::Sell => {
-                assert!(
-                    quote_paid_u.saturating_mul(maker_den) >= base_fill_u.saturating_mul(maker_num),
-                    "price below maker's minimum"
-                );
-                assert!(maker.locked_base_remaining.0 >= base_fill_u, "maker base too small");
-            }
-            Side::Buy => {
-                assert!(
-                    quote_paid_u.saturating_mul(maker_den) <= base_fill_u.saturating_mul(maker_num),
-                    "price above maker's maximum"
-                );
-                assert!(maker.locked_quote_remaining.0 >= quote_paid_u, "maker quote too small");
+        let mut market = self.market(maker.market_id);
+        let completes = base_fill_u == maker.remaining_base.0 || base_fill_u == taker.remaining_base.0;
+        assert!(completes || base_fill_u.is_multiple_of(market.lot_size), "base_fill is not a multiple of the lot size");
+        // A crossing pair of one owner is resolved by its self-trade policy instead
+        let same_owner = maker.owner_id == taker.owner_id;
+        if same_owner && matching::crosses(&taker.side, &Price::of(&taker), &Price::of(&maker)) {
+            let mode = self_trade_mode(&market, &taker);
+            if mode != SelfTradePrevention::Allow {
+                self.internal_prevent_self_trade(&mut market, &mut maker, &mut taker, mode);
+                self.save_market(&market);
+                self.internal_save_order(&maker);
+                self.internal_save_order(&taker);
+                return;
             }
         }
-        match taker.side {
-            Side::Sell => {
-                assert!(
-                    quote_paid_u.saturating_mul(taker_den) >= base_fill_u.saturating_mul(taker_num),
-                    "price below taker's minimum"
-                );
-                assert!(taker.locked_base_remaining.0 >= base_fill_u, "taker base too small");
-            }
-            Side::Buy => {
-                assert!(
-                    quote_paid_u.saturating_mul(taker_den) <= base_fill_u.saturating_mul(taker_num),
-                    "price above taker's maximum"
-                );
-                assert!(taker.locked_quote_remaining.0 >= quote_paid_u, "taker quote too small");
-            }
-        }
-
-        // Update maker and taker states and balances
-        // Seller gives base, receives quote. Buyer gives quote, receives base.
-        {
-            let (seller, buyer, seller_id, buyer_id);
-            let base_id = self.base_token_id.clone();
-            let quote_id = self.quote_token_id.clone();
-            if maker.side == Side::Sell {
-                seller = &mut maker;
-                buyer = &mut taker;
-            } else {
-                seller = &mut taker;
-                buyer = &mut maker;
-            }
-            // Deduct from locks
-            seller.locked_base_remaining = U128(seller.locked_base_remaining.0 - base_fill_u);
-            seller.remaining_base = U128(seller.remaining_base.0 - base_fill_u);
-            buyer.locked_quote_remaining = U128(buyer.locked_quote_remaining.0 - quote_paid_u);
-            buyer.remaining_base = U128(buyer.remaining_base.0 - base_fill_u);
-            seller_id = seller.owner_id.clone();
-            buyer_id = buyer.owner_id.clone();
-            // Credit balances
-            self.internal_add_balance(&seller_id, &quote_id, quote_paid_u);
-            self.internal_add_balance(&buyer_id, &base_id, base_fill_u);
-        }
-
-        // Save orders
-        if maker.remaining_base.0 == 0 { maker.status = OrderStatus::Filled; }
-        if taker.remaining_base.0 == 0 { taker.status = OrderStatus::Filled; }
-        self.orders.insert(&maker_order_id, &maker);
-        self.orders.insert(&taker_order_id, &taker);
-
-        emit_event(
-            "order_fill",
-            near_sdk::serde_json::json!({
-                "maker_order_id": maker_order_id,
-                "taker_order_id": taker_order_id,
-                "base_fill": base_fill_u.to_string(),
-                "quote_paid": quote_paid_u.to_string(),
-                "maker_remaining": maker.remaining_base.0.to_string(),
-                "taker_remaining": taker.remaining_base.0.to_string(),
-            }),
-        );
+        self.internal_fill(&mut market, &mut maker, &mut taker, base_fill_u, quote_paid_u, keeper_id.as_ref());
+        self.internal_cancel_unfillable(&mut market, &mut maker);
+        self.internal_cancel_unfillable(&mut market, &mut taker);
+        self.save_market(&market);
+        self.internal_save_order(&maker);
+        self.internal_save_order(&taker);
+        let reward = keeper_id.map_or(0, |_| self.keeper_reward(quote_paid_u));
+        self.internal_record_match(&env::predecessor_account_id(), base_fill_u, quote_paid_u, reward);
     }
 
     #[payable]
@@ -371,18 +720,13 @@ impl Contract {
                 Gas::from_tgas(25),
             )
         } else {
-            // ft_transfer
-            Promise::new(token_id.clone()).function_call(
-                "ft_transfer".to_string(),
-                near_sdk::serde_json::to_vec(&near_sdk::serde_json::json!({
-                    "receiver_id": to,
-                    "amount": U128(amount_u),
-                    "memo": memo,
-                })).unwrap(),
-                NearToken::from_yoctonear(1),
-                Gas::from_tgas(10),
-            )
-        };
+            ft_transfer(&token_id, &to, amount_u)
+        }
+        .then(
+            Self::ext(env::current_account_id())
+                .with_static_gas(GAS_FOR_RESOLVE_WITHDRAW)
+                .resolve_withdraw(caller.clone(), token_id.clone(), U128(amount_u), msg.is_some()),
+        );
 
         emit_event(
             "withdraw",
@@ -398,96 +742,113 @@ impl Contract {
         promise
     }
 
+    /// Settles a `withdraw`: whatever did not reach the receiver goes back to
+    /// `account_id`'s balance. A failed transfer refunds everything; for
+    /// `ft_transfer_call` the unused amount it returns is refunded. Returns
+    /// the amount actually withdrawn.
+    #[private]
+    pub fn resolve_withdraw(&mut self, account_id: AccountId, token_id: TokenId, amount: U128, is_call: bool) -> U128 {
+        const MAX_RESULT_LENGTH: usize = "\"+340282366920938463463374607431768211455\"".len(); // u128::MAX
+        let refund = match env::promise_result_checked(0, MAX_RESULT_LENGTH) {
+            // `ft_transfer_call` resolves to the amount the receiver kept
+            Ok(value) if is_call => {
+                let used = near_sdk::serde_json::from_slice::<U128>(&value).map_or(amount.0, |u| u.0);
+                amount.0 - used.min(amount.0)
+            }
+            // Only a failed transfer is refunded: an oversized result still
+            // means the tokens left, so it counts as fully used
+            Err(PromiseError::Failed) => amount.0,
+            _ => 0,
+        };
+        if refund > 0 {
+            self.internal_add_balance(&account_id, &token_id, refund);
+            emit_event(
+                "withdraw_failed",
+                near_sdk::serde_json::json!({
+                    "account_id": account_id,
+                    "token_id": token_id,
+                    "amount": amount.0.to_string(),
+                    "refunded": refund.to_string(),
+                }),
+            );
+        }
+        U128(amount.0 - refund)
+    }
+
     // Views
-    pub fn get_config(&self) -> (TokenId, TokenId) { (self.base_token_id.clone(), self.quote_token_id.clone()) }
+    pub fn get_best_bid(&self, market_id: MarketId) -> Option<PriceLevelView> {
+        self.market(market_id).book.best(&Side::Buy).map(|(p, l)| PriceLevelView::new(&p, &l))
+    }
+
+    pub fn get_best_ask(&self, market_id: MarketId) -> Option<PriceLevelView> {
+        self.market(market_id).book.best(&Side::Sell).map(|(p, l)| PriceLevelView::new(&p, &l))
+    }
+
+    /// Aggregated book, `levels` price levels per side, best price first.
+    pub fn get_depth(&self, market_id: MarketId, levels: u64) -> DepthView {
+        let book = self.market(market_id).book;
+        let levels = levels as usize;
+        DepthView {
+            bids: book.depth(&Side::Buy, levels).iter().map(|(p, l)| PriceLevelView::new(p, l)).collect(),
+            asks: book.depth(&Side::Sell, levels).iter().map(|(p, l)| PriceLevelView::new(p, l)).collect(),
+        }
+    }
+
+    /// Order ids resting at one price level in time priority. Any
+    /// representation of the level's price finds it.
+    pub fn get_level_orders(&self, market_id: MarketId, side: String, price_num: U128, price_den: U128, limit: u64) -> Vec<u64> {
+        assert!(price_num.0 > 0 && price_den.0 > 0, "price must be positive");
+        let price = Price::new(price_num.0, price_den.0);
+        self.market(market_id).book.level_orders(&parse_side(&side), &price, limit as usize)
+    }
 
     pub fn get_balance(&self, account_id: AccountId, token_id: TokenId) -> U128 {
         U128(self.internal_get_balance(&account_id, &token_id))
     }
 
-    pub fn get_order(&self, order_id: u64) -> Option<(u64, String, String, U128, U128, U128, U128, U128, U128, String, u64)> {
-        self.orders.get(&order_id).map(|o| (
-            o.id,
-            o.owner_id.to_string(),
-            side_str(&o.side).to_string(),
-            o.price_num,
-            o.price_den,
-            o.amount_base,
-            o.remaining_base,
-            o.locked_quote_remaining,
-            o.locked_base_remaining,
-            status_str(&o.status).to_string(),
-            o.created_at,
-        ))
+    pub fn get_order(&self, order_id: u64) -> Option<OrderView> {
+        self.orders.get(&order_id).map(OrderView::from)
     }
 
-    pub fn get_orders(&self, from_index: u64, limit: u64) -> Vec<(u64, String, String, U128, U128, U128, U128, U128, U128, String, u64)> {
+    pub fn get_orders(&self, from_index: u64, limit: u64) -> Vec<OrderView> {
         let keys: Vec<u64> = self.orders.keys_as_vector().to_vec();
-        let start = from_index as usize;
+        let start = usize::min(from_index as usize, keys.len());
         let end = usize::min(start + (limit as usize), keys.len());
-        keys[start..end].iter().map(|k| {
-            let o = self.orders.get(k).unwrap();
-            (
-                o.id,
-                o.owner_id.to_string(),
-                side_str(&o.side).to_string(),
-                o.price_num,
-                o.price_den,
-                o.amount_base,
-                o.remaining_base,
-                o.locked_quote_remaining,
-                o.locked_base_remaining,
-                status_str(&o.status).to_string(),
-                o.created_at,
-            )
-        }).collect()
+        keys[start..end].iter().map(|k| OrderView::from(self.orders.get(k).unwrap())).collect()
     }
 
-    pub fn get_orders_by_owner(&self, owner_id: AccountId) -> Vec<(u64, String, String, U128, U128, U128, U128, U128, U128, String, u64)> {
+    pub fn get_orders_by_owner(&self, owner_id: AccountId) -> Vec<OrderView> {
         if let Some(set) = self.orders_by_owner.get(&owner_id) {
-            set.iter().map(|id| {
-                let o = self.orders.get(&id).unwrap();
-                (
-                    o.id,
-                    o.owner_id.to_string(),
-                    side_str(&o.side).to_string(),
-                    o.price_num,
-                    o.price_den,
-                    o.amount_base,
-                    o.remaining_base,
-                    o.locked_quote_remaining,
-                    o.locked_base_remaining,
-                    status_str(&o.status).to_string(),
-                    o.created_at,
-                )
-            }).collect()
+            set.iter().map(|id| OrderView::from(self.orders.get(&id).unwrap())).collect()
         } else { vec![] }
     }
-}
 
-#[near_bindgen]
-impl FungibleTokenReceiver for Contract {
-    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, _msg: String) -> PromiseOrValue<U128> {
-        // Only accept deposits from the two market tokens
-        let token_contract = env::predecessor_account_id();
-        if token_contract != self.base_token_id && token_contract != self.quote_token_id {
-            return PromiseOrValue::Value(amount);
-        }
-        let amt = amount.0;
-        self.internal_add_balance(&sender_id, &token_contract, amt);
-        emit_event(
-            "deposit",
-            near_sdk::serde_json::json!({
-                "account_id": sender_id,
-                "token_id": token_contract,
-                "amount": amt.to_string(),
-            }),
-        );
-        PromiseOrValue::Value(U128(0))
+    /// Last `MAX_CLOSED_ORDERS` orders of `account_id` that were filled,
+    /// cancelled or expired, newest first.
+    pub fn get_closed_orders(&self, account_id: AccountId) -> Vec<ClosedOrderView> {
+        self.closed_orders.get(&account_id).unwrap_or_default().into_iter().map(ClosedOrderView::from).collect()
     }
 }
 
 impl Contract {
+    /// Places a limit order for `owner_id` from its balance: re-prices
+    /// post-only orders, checks the market's size limits, locks funds, then
+    /// matches against the resting book first; any remainder rests unless
+    /// IOC/FOK.
+    pub(crate) fn internal_place_order(
+        &mut self,
+        market: &mut Market,
+        owner_id: AccountId,
+        mut request: NewOrder,
+        max_matches: Option<u32>,
+    ) -> u64 {
+        request.price = self.internal_post_only_price(market, &request.side, request.time_in_force, request.price);
+        market.assert_order_size(&request);
+        let mut order = self.internal_create_order(market, owner_id, request);
+        self.internal_match_order(market, &mut order, max_matches.unwrap_or(DEFAULT_MAX_MATCHES));
+        order.id
+    }
+
     fn orders_set_for(&mut self, owner_id: &AccountId) -> UnorderedSet<u64> {
         if let Some(set) = self.orders_by_owner.get(owner_id) { return set; }
         let mut prefix = vec![];
@@ -497,36 +858,170 @@ impl Contract {
         UnorderedSet::new(near_sdk::borsh::to_vec(&bytes).unwrap())
     }
 
-    fn internal_create_order(
-        &mut self,
-        owner_id: AccountId,
-        side: Side,
-        amount_base: u128,
-        locked_quote: u128,
-        locked_base: u128,
-        price_num: u128,
-        price_den: u128,
-    ) -> u64 {
-        let id = self.next_order_id;
-        self.next_order_id += 1;
-        let order = Order {
-            id,
-            owner_id: owner_id.clone(),
-            side: side.clone(),
-            price_num: U128(price_num),
-            price_den: U128(price_den),
-            amount_base: U128(amount_base),
-            remaining_base: U128(amount_base),
-            locked_quote_remaining: U128(locked_quote),
-            locked_base_remaining: U128(locked_base),
-            status: OrderStatus::Open,
-            created_at: env::block_timestamp() / 1_000_000,
+    /// Locks the order's funds, stores it and emits `order_place`. Orders
+    /// with a trigger price start `Pending`, everything else `Open`. The
+    /// stored bytes plus a book entry allowance are charged to the owner's
+    /// storage deposit.
+    fn internal_create_order(&mut self, market: &Market, owner_id: AccountId, request: NewOrder) -> Order {
+        let token_id = market.spent_token(&request.side);
+        let lock = match request.side {
+            Side::Buy => request.max_spend_quote,
+            Side::Sell => request.amount_base,
         };
+        let bal = self.internal_get_balance(&owner_id, token_id);
+        match request.side {
+            Side::Buy => assert!(bal >= lock, "Insufficient quote balance"),
+            Side::Sell => assert!(bal >= lock, "Insufficient base balance"),
+        }
+        self.internal_sub_balance(&owner_id, token_id, lock);
+
+        let order = self.internal_new_order(market.id, owner_id.clone(), request);
+        let id = order.id;
+        let initial_storage = env::storage_usage();
         self.orders.insert(&id, &order);
         let mut set = self.orders_set_for(&owner_id);
         set.insert(&id);
         self.orders_by_owner.insert(&owner_id, &set);
-        id
+        let bytes = env::storage_usage() - initial_storage + storage::ORDER_BOOK_ENTRY_BYTES;
+        self.internal_storage_charge(&owner_id, bytes);
+
+        let mut event = near_sdk::serde_json::json!({
+            "order_id": order.id,
+            "market_id": order.market_id,
+            "owner_id": order.owner_id,
+            "side": side_str(&order.side),
+            "price_num": order.price_num,
+            "price_den": order.price_den,
+            "time_in_force": tif_str(&order.time_in_force),
+            "order_type": order_type_str(&order.order_type),
+        });
+        let data = event.as_object_mut().unwrap();
+        if !order.is_quote_sized() {
+            data.insert("amount_base".into(), near_sdk::serde_json::json!(order.amount_base));
+        }
+        if order.side == Side::Buy {
+            data.insert("max_spend_quote".into(), near_sdk::serde_json::json!(U128(lock)));
+        }
+        if let Some(expires_at) = order.expires_at {
+            data.insert("expires_at".into(), near_sdk::serde_json::json!(expires_at));
+        }
+        if let Some(trigger) = order.trigger_price {
+            data.insert("trigger_price_num".into(), near_sdk::serde_json::json!(U128(trigger.num)));
+            data.insert("trigger_price_den".into(), near_sdk::serde_json::json!(U128(trigger.den)));
+        }
+        if let Some(integrator_id) = &order.integrator_id {
+            data.insert("integrator_id".into(), near_sdk::serde_json::json!(integrator_id));
+            data.insert("integrator_fee_bps".into(), near_sdk::serde_json::json!(order.integrator_fee_bps));
+        }
+        if let Some(mode) = order.self_trade_prevention {
+            data.insert("self_trade_prevention".into(), near_sdk::serde_json::json!(self_trade_prevention_str(mode)));
+        }
+        emit_event("order_place", event);
+        order
+    }
+
+    /// Builds an order with the next id, holding the funds `request` locks.
+    /// The caller is responsible for taking those funds from somewhere.
+    pub(crate) fn internal_new_order(&mut self, market_id: MarketId, owner_id: AccountId, request: NewOrder) -> Order {
+        let id = self.next_order_id;
+        self.next_order_id += 1;
+        Order::new(id, market_id, owner_id, request)
+    }
+
+    /// Persists `order`, or once it is filled, cancelled or expired removes
+    /// it from `orders` and its owner's set, refunds its storage charge (if
+    /// it was charged) and records it in the owner's closed orders, charged
+    /// to the owner even past its deposit.
+    pub(crate) fn internal_save_order(&mut self, order: &Order) {
+        if order.status == OrderStatus::Open || order.status == OrderStatus::Pending {
+            self.orders.insert(&order.id, order);
+            return;
+        }
+        let owner_id = &order.owner_id;
+        let initial_storage = env::storage_usage();
+        self.orders.remove(&order.id);
+        let mut set = self.orders_set_for(owner_id);
+        set.remove(&order.id);
+        if set.is_empty() {
+            self.orders_by_owner.remove(owner_id);
+        } else {
+            self.orders_by_owner.insert(owner_id, &set);
+        }
+        if order.storage_charged {
+            let freed = initial_storage - env::storage_usage();
+            self.internal_storage_release(owner_id, freed + storage::ORDER_BOOK_ENTRY_BYTES);
+        }
+
+        let initial_storage = env::storage_usage();
+        let mut closed = self.closed_orders.get(owner_id).unwrap_or_default();
+        closed.insert(0, ClosedOrder {
+            id: order.id,
+            side: order.side.clone(),
+            status: order.status.clone(),
+            price: Price::of(order),
+            closed_at: now_ms(),
+        });
+        closed.truncate(MAX_CLOSED_ORDERS);
+        self.closed_orders.insert(owner_id, &closed);
+        let used = env::storage_usage().saturating_sub(initial_storage);
+        // Orders often close in another account's call (a fill, an expiry),
+        // which must not fail over the owner's deposit
+        self.internal_storage_force_charge(owner_id, used);
+    }
+
+    /// Returns whatever is still locked by `order` to its owner, as
+    /// `(quote, base)` released.
+    pub(crate) fn internal_release_locks(&mut self, market: &Market, order: &mut Order) -> (u128, u128) {
+        let refund_quote = order.locked_quote_remaining.0;
+        if refund_quote > 0 {
+            self.internal_add_balance(&order.owner_id, &market.quote_token_id, refund_quote);
+        }
+        let refund_base = order.locked_base_remaining.0;
+        if refund_base > 0 {
+            self.internal_add_balance(&order.owner_id, &market.base_token_id, refund_base);
+        }
+        order.locked_quote_remaining = U128(0);
+        order.locked_base_remaining = U128(0);
+        (refund_quote, refund_base)
+    }
+
+    /// Takes an open or pending order off the books, refunds its locks and
+    /// leaves it in the terminal `status`. Callers persist it.
+    fn internal_close(&mut self, market: &mut Market, order: &mut Order, status: OrderStatus) -> (u128, u128) {
+        if order.status == OrderStatus::Pending {
+            market.stops.remove(order);
+        }
+        market.book.remove(order);
+        let released = self.internal_release_locks(market, order);
+        order.status = status;
+        order.remaining_base = U128(0);
+        released
+    }
+
+    pub(crate) fn internal_cancel(&mut self, market: &mut Market, order: &mut Order, reason: &str) {
+        self.internal_close(market, order, OrderStatus::Cancelled);
+        emit_event(
+            "order_cancel",
+            near_sdk::serde_json::json!({
+                "order_id": order.id,
+                "owner_id": order.owner_id,
+                "reason": reason,
+            }),
+        );
+    }
+
+    pub(crate) fn internal_expire(&mut self, market: &mut Market, order: &mut Order) {
+        let (released_quote, released_base) = self.internal_close(market, order, OrderStatus::Expired);
+        emit_event(
+            "order_expire",
+            near_sdk::serde_json::json!({
+                "order_id": order.id,
+                "owner_id": order.owner_id,
+                "expires_at": order.expires_at,
+                "released_quote": U128(released_quote),
+                "released_base": U128(released_base),
+            }),
+        );
     }
 
     fn internal_get_balance(&self, account_id: &AccountId, token_id: &TokenId) -> u128 {
@@ -534,19 +1029,159 @@ impl Contract {
         self.balances.get(&near_sdk::borsh::to_vec(&key).unwrap()).unwrap_or(0)
     }
 
+    /// Credits `amount`. A new balance entry is charged to the account even
+    /// past its deposit, since fills and refunds can't be refused; see
+    /// `internal_storage_force_charge`.
     fn internal_add_balance(&mut self, account_id: &AccountId, token_id: &TokenId, amount: u128) {
+        if amount == 0 {
+            return;
+        }
         let key = BalanceKey { account_id: account_id.clone(), token_id: token_id.clone() };
         let k = near_sdk::borsh::to_vec(&key).unwrap();
-        let cur = self.balances.get(&k).unwrap_or(0);
-        self.balances.insert(&k, &(cur + amount));
+        match self.balances.get(&k) {
+            Some(cur) => {
+                self.balances.insert(&k, &(cur + amount));
+            }
+            None => {
+                let initial_storage = env::storage_usage();
+                self.balances.insert(&k, &amount);
+                self.internal_storage_force_charge(account_id, env::storage_usage() - initial_storage);
+            }
+        }
     }
 
+    /// Debits `amount`, removing the entry and releasing its charge once
+    /// the balance is zero.
     fn internal_sub_balance(&mut self, account_id: &AccountId, token_id: &TokenId, amount: u128) {
         let key = BalanceKey { account_id: account_id.clone(), token_id: token_id.clone() };
         let k = near_sdk::borsh::to_vec(&key).unwrap();
         let cur = self.balances.get(&k).unwrap_or(0);
         assert!(cur >= amount, "insufficient balance");
         let new_bal = cur - amount;
-        if new_bal == 0 { self.balances.remove(&k); } else { self.balances.insert(&k, &new_bal); }
+        if new_bal == 0 {
+            let initial_storage = env::storage_usage();
+            self.balances.remove(&k);
+            self.internal_storage_release(account_id, initial_storage - env::storage_usage());
+        } else {
+            self.balances.insert(&k, &new_bal);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{get_logs, VMContextBuilder};
+    use near_sdk::{test_vm_config, testing_env, PromiseResult, RuntimeFeesConfig};
+
+    use super::*;
+    use crate::test_utils::{acc, context, deposit, new_market, register};
+
+    /// Calls into `ob.near` as `predecessor` with 1 yocto attached at
+    /// block time `ms`.
+    fn context_at(predecessor: &str, ms: u64) {
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(acc("ob.near"))
+            .predecessor_account_id(acc(predecessor))
+            .attached_deposit(NearToken::from_yoctonear(1))
+            .block_timestamp(ms * 1_000_000)
+            .build());
+    }
+
+    /// Alice rests a sell of 5 of her 10 base expiring at 1000 ms.
+    fn expiring_ask() -> (Contract, MarketId) {
+        let (mut contract, market_id) = new_market();
+        register(&mut contract, "alice.near");
+        deposit(&mut contract, "alice.near", "base.near", 10);
+        context("alice.near");
+        contract.place_order(market_id, "sell".into(), U128(5), None, U128(1), U128(1), None, None, Some(1000), None, None, None);
+        (contract, market_id)
+    }
+
+    #[test]
+    fn expired_makers_are_skipped_by_the_sweep() {
+        let (mut contract, market_id) = expiring_ask();
+        register(&mut contract, "bob.near");
+        deposit(&mut contract, "bob.near", "quote.near", 5);
+        context_at("bob.near", 2000);
+        contract.place_order(market_id, "buy".into(), U128(5), Some(U128(5)), U128(1), U128(1), None, None, None, None, None, None);
+        assert_eq!(contract.get_balance(acc("bob.near"), acc("base.near")).0, 0);
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("base.near")).0, 10);
+        assert_eq!(contract.get_closed_orders(acc("alice.near"))[0].status, "expired");
+        assert_eq!(contract.get_best_bid(market_id).unwrap().total_base.0, 5);
+    }
+
+    #[test]
+    fn expire_orders_reaps_only_due_orders_and_returns_their_lock() {
+        let (mut contract, _) = expiring_ask();
+        context_at("carol.near", 999);
+        assert!(contract.expire_orders(vec![0]).is_empty());
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("base.near")).0, 5);
+        context_at("carol.near", 1000);
+        assert_eq!(contract.expire_orders(vec![0, 7]), vec![0]);
+        assert!(contract.get_order(0).is_none());
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("base.near")).0, 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "expires_at must be in the future")]
+    fn past_expiry_is_rejected_at_placement() {
+        let (mut contract, market_id) = new_market();
+        register(&mut contract, "alice.near");
+        deposit(&mut contract, "alice.near", "base.near", 10);
+        context_at("alice.near", 2000);
+        contract.place_order(market_id, "sell".into(), U128(5), None, U128(1), U128(1), None, None, Some(1000), None, None, None);
+    }
+
+    #[test]
+    fn equal_prices_share_a_level_in_time_priority() {
+        let (mut contract, market_id) = new_market();
+        register(&mut contract, "alice.near");
+        deposit(&mut contract, "alice.near", "base.near", 10);
+        context("alice.near");
+        contract.place_order(market_id, "sell".into(), U128(5), None, U128(20), U128(2), None, None, None, None, None, None);
+        contract.place_order(market_id, "sell".into(), U128(5), None, U128(10), U128(1), None, None, None, None, None, None);
+        assert!(get_logs()[0].contains(r#""price_num":"10","price_den":"1""#));
+        assert_eq!(contract.get_level_orders(market_id, "sell".into(), U128(20), U128(2), 10), vec![0, 1]);
+        let depth = contract.get_depth(market_id, 10);
+        assert_eq!(depth.asks.len(), 1);
+    }
+
+    /// Alice, holding 100 base.near, withdraws 40 of it and the transfer
+    /// resolves to `result`. Returns what `resolve_withdraw` reports sent.
+    fn resolve_alice_withdraw(result: PromiseResult, is_call: bool) -> (Contract, U128) {
+        let (mut contract, _) = new_market();
+        register(&mut contract, "alice.near");
+        deposit(&mut contract, "alice.near", "base.near", 100);
+        context("alice.near");
+        let msg = is_call.then(String::new);
+        contract.withdraw(acc("base.near"), U128(40), None, msg).detach();
+        let context = VMContextBuilder::new()
+            .current_account_id(acc("ob.near"))
+            .predecessor_account_id(acc("ob.near"))
+            .build();
+        testing_env!(context, test_vm_config(), RuntimeFeesConfig::test(), Default::default(), vec![result]);
+        let sent = contract.resolve_withdraw(acc("alice.near"), acc("base.near"), U128(40), is_call);
+        (contract, sent)
+    }
+
+    #[test]
+    fn failed_withdraw_is_refunded() {
+        let (contract, sent) = resolve_alice_withdraw(PromiseResult::Failed, false);
+        assert_eq!(sent.0, 0);
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("base.near")).0, 100);
+    }
+
+    #[test]
+    fn withdraw_call_refunds_what_the_receiver_returns() {
+        let (contract, sent) = resolve_alice_withdraw(PromiseResult::Successful(b"\"30\"".to_vec()), true);
+        assert_eq!(sent.0, 30);
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("base.near")).0, 70);
+    }
+
+    #[test]
+    fn oversized_withdraw_result_counts_as_fully_used() {
+        let (contract, sent) = resolve_alice_withdraw(PromiseResult::Successful(vec![b'0'; 100]), true);
+        assert_eq!(sent.0, 40);
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("base.near")).0, 60);
     }
 }
@@ -0,0 +1,241 @@
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, near_bindgen};
+
+use crate::matching::fills_a_lot;
+use crate::math::{checked_mul_div_floor, gcd};
+use crate::self_trade::self_trade_prevention_str;
+use crate::{
+    emit_event, Contract, ContractExt, NewOrder, OrderBook, Price, Role, SelfTradePrevention, Side, StopBook, StorageKey,
+    TokenId,
+};
+
+pub type MarketId = u32;
+
+/// One listed pair with its own book, stop orders and last trade price.
+/// Balances are per account and token, shared by every market.
+#[derive(BorshDeserialize, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct Market {
+    pub id: MarketId,
+    pub base_token_id: TokenId,
+    pub quote_token_id: TokenId,
+    pub book: OrderBook,
+    pub stops: StopBook,
+    pub last_trade_price: Option<Price>,
+    /// Self-trade policy for orders placed without their own.
+    pub self_trade_prevention: SelfTradePrevention,
+    /// Limit prices must be whole multiples of this, if set.
+    pub tick_size: Option<Price>,
+    /// Limit order sizes and `execute` fills must be whole multiples of this.
+    pub lot_size: u128,
+    /// Smallest `amount_base * price`, in quote, a limit order may have.
+    pub min_notional: u128,
+}
+
+impl Market {
+    pub(crate) fn new(id: MarketId, base_token_id: TokenId, quote_token_id: TokenId) -> Self {
+        Self {
+            id,
+            base_token_id,
+            quote_token_id,
+            book: OrderBook::new(
+                StorageKey::MarketBids { market_id: id },
+                StorageKey::MarketAsks { market_id: id },
+                StorageKey::MarketBookLinks { market_id: id },
+            ),
+            stops: StopBook::new(StorageKey::MarketStopBuys { market_id: id }, StorageKey::MarketStopSells { market_id: id }),
+            last_trade_price: None,
+            self_trade_prevention: SelfTradePrevention::Allow,
+            tick_size: None,
+            lot_size: 1,
+            min_notional: 0,
+        }
+    }
+
+    /// Rejects a limit order off the tick, off the lot, too small to fill a
+    /// lot at its price or below the minimum notional.
+    pub(crate) fn assert_order_size(&self, request: &NewOrder) {
+        if let Some(tick) = &self.tick_size {
+            assert!(is_multiple(&request.price, tick), "price is not a multiple of the tick size");
+        }
+        assert!(request.amount_base.is_multiple_of(self.lot_size), "amount_base is not a multiple of the lot size");
+        assert!(
+            fills_a_lot(&request.side, request.amount_base, request.max_spend_quote, &request.price, self.lot_size),
+            "order too small to fill a lot at its price"
+        );
+        let mut notional = checked_mul_div_floor(request.amount_base, request.price.num, request.price.den)
+            .expect("order notional too large");
+        if request.side == Side::Buy {
+            // A buy can't trade more quote than it locks
+            notional = notional.min(request.max_spend_quote);
+        }
+        assert!(notional >= self.min_notional, "order below minimum notional");
+    }
+
+    /// Token an order on `side` pays with: quote for buys, base for sells.
+    pub fn spent_token(&self, side: &Side) -> &TokenId {
+        match side {
+            Side::Buy => &self.quote_token_id,
+            Side::Sell => &self.base_token_id,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MarketView {
+    pub market_id: MarketId,
+    pub base_token_id: TokenId,
+    pub quote_token_id: TokenId,
+    pub self_trade_prevention: String,
+    pub tick_size_num: Option<U128>,
+    pub tick_size_den: Option<U128>,
+    pub lot_size: U128,
+    pub min_notional: U128,
+}
+
+impl From<&Market> for MarketView {
+    fn from(market: &Market) -> Self {
+        Self {
+            market_id: market.id,
+            base_token_id: market.base_token_id.clone(),
+            quote_token_id: market.quote_token_id.clone(),
+            self_trade_prevention: self_trade_prevention_str(market.self_trade_prevention).to_string(),
+            tick_size_num: market.tick_size.map(|t| U128(t.num)),
+            tick_size_den: market.tick_size.map(|t| U128(t.den)),
+            lot_size: U128(market.lot_size),
+            min_notional: U128(market.min_notional),
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Lists a new pair and returns its id. Owner or operator.
+    #[payable]
+    pub fn add_market(&mut self, base_token_id: TokenId, quote_token_id: TokenId) -> MarketId {
+        assert_one_yocto();
+        self.assert_role(Role::Operator);
+        assert_ne!(base_token_id, quote_token_id, "base and quote tokens must differ");
+        let listed = self.markets.values().any(|m| m.base_token_id == base_token_id && m.quote_token_id == quote_token_id);
+        assert!(!listed, "market already listed");
+        let market_id = self.next_market_id;
+        self.next_market_id += 1;
+        self.markets.insert(&market_id, &Market::new(market_id, base_token_id.clone(), quote_token_id.clone()));
+        self.listed_tokens.insert(&base_token_id);
+        self.listed_tokens.insert(&quote_token_id);
+        emit_event(
+            "market_add",
+            near_sdk::serde_json::json!({
+                "market_id": market_id,
+                "base_token_id": base_token_id,
+                "quote_token_id": quote_token_id,
+                "by": env::predecessor_account_id(),
+            }),
+        );
+        market_id
+    }
+
+    /// Owner or operator. Replaces the market's order size limits: an
+    /// optional tick size (as `tick_size_num / tick_size_den` quote per
+    /// base), a lot size (default 1) and a minimum notional in quote
+    /// (default 0). Orders already resting are not affected.
+    #[payable]
+    pub fn set_market_limits(
+        &mut self,
+        market_id: MarketId,
+        tick_size_num: Option<U128>,
+        tick_size_den: Option<U128>,
+        lot_size: Option<U128>,
+        min_notional: Option<U128>,
+    ) {
+        assert_one_yocto();
+        self.assert_role(Role::Operator);
+        let mut market = self.market(market_id);
+        market.tick_size = match (tick_size_num, tick_size_den) {
+            (Some(num), Some(den)) => {
+                assert!(num.0 > 0 && den.0 > 0, "tick size must be positive");
+                Some(Price::new(num.0, den.0))
+            }
+            (None, None) => None,
+            _ => env::panic_str("tick_size_num and tick_size_den go together"),
+        };
+        market.lot_size = lot_size.map_or(1, |l| l.0);
+        assert!(market.lot_size > 0, "lot size must be positive");
+        market.min_notional = min_notional.map_or(0, |n| n.0);
+        self.save_market(&market);
+        emit_event(
+            "market_limits",
+            near_sdk::serde_json::json!({
+                "market_id": market_id,
+                "tick_size_num": tick_size_num,
+                "tick_size_den": tick_size_den,
+                "lot_size": U128(market.lot_size),
+                "min_notional": U128(market.min_notional),
+                "by": env::predecessor_account_id(),
+            }),
+        );
+    }
+
+    pub fn get_market(&self, market_id: MarketId) -> Option<MarketView> {
+        self.markets.get(&market_id).as_ref().map(MarketView::from)
+    }
+
+    pub fn get_markets(&self, from_index: u64, limit: u64) -> Vec<MarketView> {
+        self.markets.values().skip(from_index as usize).take(limit as usize).map(|m| MarketView::from(&m)).collect()
+    }
+}
+
+impl Contract {
+    pub(crate) fn market(&self, market_id: MarketId) -> Market {
+        self.markets.get(&market_id).expect("market not found")
+    }
+
+    /// Writes back a market loaded with `market` after its book, stops or
+    /// last price changed.
+    pub(crate) fn save_market(&mut self, market: &Market) {
+        self.markets.insert(&market.id, market);
+    }
+}
+
+/// Whether `price` is a whole multiple of `tick`, without overflowing:
+/// `(a / b) / (c / d)` is whole iff, with common factors of `a, c` and of
+/// `d, b` cancelled, `b` divides `a` and `c` divides `d`.
+fn is_multiple(price: &Price, tick: &Price) -> bool {
+    let g = gcd(price.num, tick.num);
+    let (a, c) = (price.num / g, tick.num / g);
+    let g = gcd(tick.den, price.den);
+    let (d, b) = (tick.den / g, price.den / g);
+    a % b == 0 && d % c == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{acc, context, new_market, register};
+
+    #[test]
+    #[should_panic(expected = "order below minimum notional")]
+    fn buy_notional_is_capped_by_its_lock() {
+        let (mut contract, market_id) = new_market();
+        context("owner.near");
+        contract.set_market_limits(market_id, None, None, None, Some(U128(100)));
+        register(&mut contract, "bob.near");
+        contract.internal_add_balance(&acc("bob.near"), &acc("quote.near"), 50);
+        context("bob.near");
+        // 100 base at 1 is 100 quote, but only 50 is locked
+        contract.place_order(market_id, "buy".into(), U128(100), Some(U128(50)), U128(1), U128(1), None, None, None, None, None, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "order notional too large")]
+    fn overflowing_notional_is_rejected() {
+        let (mut contract, market_id) = new_market();
+        register(&mut contract, "alice.near");
+        contract.internal_add_balance(&acc("alice.near"), &acc("base.near"), u128::MAX);
+        context("alice.near");
+        contract.place_order(market_id, "sell".into(), U128(u128::MAX), None, U128(2), U128(1), None, None, None, None, None, None);
+    }
+}
@@ -0,0 +1,131 @@
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, near_bindgen, AccountId};
+
+use crate::math::mul_div_floor;
+use crate::{emit_event, Contract, ContractExt, Role};
+
+/// Upper bound on the keeper reward: 5% of the quote paid in a fill.
+pub const MAX_KEEPER_REWARD_BPS: u16 = 500;
+pub(crate) const BPS_DENOMINATOR: u128 = 10_000;
+
+/// Who may call `execute`. In `Whitelist` mode only the owner and `matcher`
+/// role holders; in `Permissionless` mode anyone, earning the keeper reward.
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, PartialEq, Debug)]
+#[borsh(crate = "near_sdk::borsh")]
+pub enum MatcherMode {
+    Whitelist,
+    Permissionless,
+}
+
+fn parse_matcher_mode(s: &str) -> MatcherMode {
+    match s.to_ascii_lowercase().as_str() {
+        "whitelist" => MatcherMode::Whitelist,
+        "permissionless" => MatcherMode::Permissionless,
+        _ => env::panic_str("invalid matcher mode"),
+    }
+}
+
+fn matcher_mode_str(mode: MatcherMode) -> &'static str {
+    match mode {
+        MatcherMode::Whitelist => "whitelist",
+        MatcherMode::Permissionless => "permissionless",
+    }
+}
+
+/// Running totals of the fills an account submitted through `execute`.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Default)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub struct MatcherStats {
+    pub fills: u64,
+    pub base_volume: U128,
+    pub quote_volume: U128,
+    pub rewards: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct MatcherConfigView {
+    pub mode: String,
+    pub keeper_reward_bps: u16,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Owner or operator. `keeper_reward_bps` of each fill's quote goes to
+    /// the `execute` caller in permissionless mode.
+    #[payable]
+    pub fn set_matcher_config(&mut self, mode: String, keeper_reward_bps: u16) {
+        assert_one_yocto();
+        self.assert_role(Role::Operator);
+        assert!(keeper_reward_bps <= MAX_KEEPER_REWARD_BPS, "keeper reward too high");
+        self.matcher_mode = parse_matcher_mode(&mode);
+        self.keeper_reward_bps = keeper_reward_bps;
+        emit_event(
+            "matcher_config",
+            near_sdk::serde_json::json!({
+                "mode": matcher_mode_str(self.matcher_mode),
+                "keeper_reward_bps": keeper_reward_bps,
+                "by": env::predecessor_account_id(),
+            }),
+        );
+    }
+
+    pub fn get_matcher_config(&self) -> MatcherConfigView {
+        MatcherConfigView {
+            mode: matcher_mode_str(self.matcher_mode).to_string(),
+            keeper_reward_bps: self.keeper_reward_bps,
+        }
+    }
+
+    pub fn get_matcher_stats(&self, account_id: AccountId) -> Option<MatcherStats> {
+        self.matcher_stats.get(&account_id)
+    }
+}
+
+impl Contract {
+    /// Checks the `execute` caller against the matcher mode. Returns the
+    /// account to reward, if any.
+    pub(crate) fn internal_check_matcher(&self) -> Option<AccountId> {
+        match self.matcher_mode {
+            MatcherMode::Whitelist => {
+                self.assert_role(Role::Matcher);
+                None
+            }
+            MatcherMode::Permissionless => {
+                let caller = env::predecessor_account_id();
+                if self.keeper_reward_bps == 0 {
+                    return None;
+                }
+                assert!(self.is_registered(&caller), "keeper must be registered to earn rewards");
+                Some(caller)
+            }
+        }
+    }
+
+    /// Share of `quote_paid` owed to the keeper of a fill.
+    pub(crate) fn keeper_reward(&self, quote_paid: u128) -> u128 {
+        mul_div_floor(quote_paid, self.keeper_reward_bps as u128, BPS_DENOMINATOR)
+    }
+
+    /// Adds a fill to `matcher_id`'s stats, charged to its storage deposit.
+    /// Unregistered callers keep no stats.
+    pub(crate) fn internal_record_match(&mut self, matcher_id: &AccountId, base: u128, quote: u128, reward: u128) {
+        if !self.is_registered(matcher_id) {
+            return;
+        }
+        let initial_storage = env::storage_usage();
+        let mut stats = self.matcher_stats.get(matcher_id).unwrap_or_default();
+        stats.fills += 1;
+        stats.base_volume = U128(stats.base_volume.0 + base);
+        stats.quote_volume = U128(stats.quote_volume.0 + quote);
+        stats.rewards = U128(stats.rewards.0 + reward);
+        self.matcher_stats.insert(matcher_id, &stats);
+        let used = env::storage_usage().saturating_sub(initial_storage);
+        if used > 0 {
+            self.internal_storage_charge(matcher_id, used);
+        }
+    }
+}
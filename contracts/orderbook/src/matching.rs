@@ -0,0 +1,644 @@
+use std::cmp::Ordering;
+
+use near_sdk::json_types::U128;
+use near_sdk::{env, AccountId};
+
+use crate::book::Price;
+use crate::math::{checked_mul_div_floor, gcd, mul_cmp, mul_div_ceil, mul_div_floor};
+use crate::self_trade::self_trade_mode;
+use crate::{
+    emit_event, market_request, now_ms, Contract, Market, Order, OrderStatus, OrderType, SelfTradePrevention, Side,
+    TimeInForce, TokenId,
+};
+
+/// Default cap on maker orders an incoming order may match in `place_order`.
+pub const DEFAULT_MAX_MATCHES: u32 = 16;
+/// Id of the taker a swap trades through. It is never stored and stays out
+/// of the order id sequence.
+const SWAP_ORDER_ID: u64 = u64::MAX;
+
+impl Contract {
+    /// Checks a fill against both orders' limits and locks, then moves the
+    /// funds and updates both orders and the book. An order the fill
+    /// completes gets its leftover lock (e.g. quote saved by filling below a
+    /// buy limit) back at once. A `keeper_id` is paid the keeper reward out
+    /// of the seller's quote, and both sides' trading fees go to the
+    /// treasury. Callers persist the orders.
+    pub(crate) fn internal_fill(
+        &mut self,
+        market: &mut Market,
+        maker: &mut Order,
+        taker: &mut Order,
+        base_fill_u: u128,
+        quote_paid_u: u128,
+        keeper_id: Option<&AccountId>,
+    ) {
+        // Enforce price limits for both maker and taker, compared exactly in
+        // 256 bits: quote / base against num / den
+        let (maker_num, maker_den) = (maker.price_num.0, maker.price_den.0);
+        let (taker_num, taker_den) = (taker.price_num.0, taker.price_den.0);
+
+        match maker.side {
+            Side::Sell => {
+                assert!(
+                    mul_cmp(quote_paid_u, maker_den, base_fill_u, maker_num) != Ordering::Less,
+                    "price below maker's minimum"
+                );
+                assert!(maker.locked_base_remaining.0 >= base_fill_u, "maker base too small");
+            }
+            Side::Buy => {
+                assert!(
+                    mul_cmp(quote_paid_u, maker_den, base_fill_u, maker_num) != Ordering::Greater,
+                    "price above maker's maximum"
+                );
+                assert!(maker.locked_quote_remaining.0 >= quote_paid_u, "maker quote too small");
+            }
+        }
+        match taker.side {
+            Side::Sell => {
+                assert!(
+                    mul_cmp(quote_paid_u, taker_den, base_fill_u, taker_num) != Ordering::Less,
+                    "price below taker's minimum"
+                );
+                assert!(taker.locked_base_remaining.0 >= base_fill_u, "taker base too small");
+            }
+            Side::Buy => {
+                assert!(
+                    mul_cmp(quote_paid_u, taker_den, base_fill_u, taker_num) != Ordering::Greater,
+                    "price above taker's maximum"
+                );
+                assert!(taker.locked_quote_remaining.0 >= quote_paid_u, "taker quote too small");
+            }
+        }
+
+        let keeper_reward = keeper_id.map_or(0, |_| self.keeper_reward(quote_paid_u));
+        // Each side pays its fee in what it receives: quote for the seller, base for the buyer
+        let maker_sells = maker.side == Side::Sell;
+        let quote_id = &market.quote_token_id;
+        let (seller, buyer) = if maker_sells { (&*maker, &*taker) } else { (&*taker, &*maker) };
+        let seller_fee = self.trading_fee(&seller.owner_id, quote_id, quote_paid_u, maker_sells);
+        let buyer_fee = self.trading_fee(&buyer.owner_id, quote_id, base_fill_u, !maker_sells);
+        let seller_integrator_fee = self.integrator_fee(seller, quote_id, quote_paid_u);
+        let buyer_integrator_fee = self.integrator_fee(buyer, &market.base_token_id, base_fill_u);
+        let (maker_fee, taker_fee) = if maker_sells { (seller_fee, buyer_fee) } else { (buyer_fee, seller_fee) };
+        let (maker_integrator_fee, taker_integrator_fee) = if maker_sells {
+            (seller_integrator_fee, buyer_integrator_fee)
+        } else {
+            (buyer_integrator_fee, seller_integrator_fee)
+        };
+
+        // Update maker and taker states and balances
+        // Seller gives base, receives quote. Buyer gives quote, receives base.
+        {
+            let (seller, buyer, seller_id, buyer_id);
+            if maker.side == Side::Sell {
+                seller = &mut *maker;
+                buyer = &mut *taker;
+            } else {
+                seller = &mut *taker;
+                buyer = &mut *maker;
+            }
+            // Deduct from locks
+            seller.locked_base_remaining = U128(seller.locked_base_remaining.0 - base_fill_u);
+            seller.remaining_base = U128(seller.remaining_base.0 - base_fill_u);
+            buyer.locked_quote_remaining = U128(buyer.locked_quote_remaining.0 - quote_paid_u);
+            if !buyer.is_quote_sized() {
+                buyer.remaining_base = U128(buyer.remaining_base.0 - base_fill_u);
+            }
+            seller_id = seller.owner_id.clone();
+            buyer_id = buyer.owner_id.clone();
+            // Credit balances
+            self.internal_add_balance(
+                &seller_id,
+                &market.quote_token_id,
+                quote_paid_u - keeper_reward - seller_fee - seller_integrator_fee,
+            );
+            self.internal_add_balance(&buyer_id, &market.base_token_id, base_fill_u - buyer_fee - buyer_integrator_fee);
+            if seller_integrator_fee > 0 {
+                self.internal_add_balance(seller.integrator_id.as_ref().unwrap(), &market.quote_token_id, seller_integrator_fee);
+            }
+            if buyer_integrator_fee > 0 {
+                self.internal_add_balance(buyer.integrator_id.as_ref().unwrap(), &market.base_token_id, buyer_integrator_fee);
+            }
+            if let Some(keeper_id) = keeper_id {
+                self.internal_add_balance(keeper_id, &market.quote_token_id, keeper_reward);
+            }
+            let seller_fee = self.internal_referral_credit(market.id, &seller_id, &market.quote_token_id, seller_fee);
+            let buyer_fee = self.internal_referral_credit(market.id, &buyer_id, &market.base_token_id, buyer_fee);
+            self.internal_add_treasury(&market.quote_token_id, seller_fee);
+            self.internal_add_treasury(&market.base_token_id, buyer_fee);
+            self.internal_record_volume(&seller_id, &market.quote_token_id, quote_paid_u);
+            // A self-trade counts once towards the owner's fee tier
+            if buyer_id != seller_id {
+                self.internal_record_volume(&buyer_id, &market.quote_token_id, quote_paid_u);
+            }
+        }
+
+        market.last_trade_price = Some(Price::new(quote_paid_u, base_fill_u));
+
+        if maker.base_capacity() == 0 { maker.status = OrderStatus::Filled; }
+        if taker.base_capacity() == 0 { taker.status = OrderStatus::Filled; }
+        market.book.fill(maker, base_fill_u);
+        market.book.fill(taker, base_fill_u);
+        let (maker_refund_quote, maker_refund_base) = if maker.status == OrderStatus::Filled {
+            self.internal_release_locks(market, maker)
+        } else {
+            (0, 0)
+        };
+        let (taker_refund_quote, taker_refund_base) = if taker.status == OrderStatus::Filled {
+            self.internal_release_locks(market, taker)
+        } else {
+            (0, 0)
+        };
+
+        let mut event = near_sdk::serde_json::json!({
+            "market_id": market.id,
+            "maker_order_id": maker.id,
+            "taker_order_id": (taker.id != SWAP_ORDER_ID).then_some(taker.id),
+            "base_fill": base_fill_u.to_string(),
+            "quote_paid": quote_paid_u.to_string(),
+            "maker_remaining": maker.remaining_base.0.to_string(),
+            "taker_remaining": taker.remaining_base.0.to_string(),
+            "maker_refund_quote": maker_refund_quote.to_string(),
+            "maker_refund_base": maker_refund_base.to_string(),
+            "taker_refund_quote": taker_refund_quote.to_string(),
+            "taker_refund_base": taker_refund_base.to_string(),
+            "maker_fee": maker_fee.to_string(),
+            "taker_fee": taker_fee.to_string(),
+            "maker_integrator_fee": maker_integrator_fee.to_string(),
+            "taker_integrator_fee": taker_integrator_fee.to_string(),
+        });
+        let data = event.as_object_mut().unwrap();
+        if let Some(integrator_id) = &maker.integrator_id {
+            data.insert("maker_integrator_id".into(), near_sdk::serde_json::json!(integrator_id));
+        }
+        if let Some(integrator_id) = &taker.integrator_id {
+            data.insert("taker_integrator_id".into(), near_sdk::serde_json::json!(integrator_id));
+        }
+        if taker.id == SWAP_ORDER_ID {
+            data.insert("swap".into(), near_sdk::serde_json::json!(true));
+        }
+        if let Some(keeper_id) = keeper_id {
+            data.insert("keeper_id".into(), near_sdk::serde_json::json!(keeper_id));
+            data.insert("keeper_reward".into(), near_sdk::serde_json::json!(U128(keeper_reward)));
+        }
+        emit_event("order_fill", event);
+    }
+
+    /// Sweeps the opposite side of the book with a freshly placed order in
+    /// price-time priority, making at most `max_matches` fills. Expired makers
+    /// met on the way are expired and count towards the cap. Whatever is left
+    /// of the order then rests in the book unless it can no longer be filled,
+    /// or for IOC/FOK is released back to the owner.
+    pub(crate) fn internal_match_order(&mut self, market: &mut Market, taker: &mut Order, max_matches: u32) {
+        self.internal_sweep(market, taker, max_matches);
+        match taker.time_in_force {
+            TimeInForce::Ioc | TimeInForce::Fok => {
+                if taker.status == OrderStatus::Open {
+                    assert!(taker.time_in_force != TimeInForce::Fok, "fill-or-kill order not fully filled");
+                    let reason = match taker.order_type {
+                        OrderType::Limit => "unfilled_ioc",
+                        OrderType::Market => "unfilled_market",
+                    };
+                    self.internal_cancel(market, taker, reason);
+                }
+            }
+            TimeInForce::Gtc | TimeInForce::PostOnly | TimeInForce::PostOnlySlide => {
+                if taker.status == OrderStatus::Open && !self.internal_cancel_unfillable(market, taker) {
+                    market.book.insert(taker);
+                }
+            }
+        }
+        self.internal_save_order(taker);
+    }
+
+    /// The matching loop of `internal_match_order`: fills `taker` against the
+    /// best opposite orders until it is filled, stops crossing or has made
+    /// `max_matches` fills. Makers that can no longer be filled are cancelled
+    /// on the way, or right after the fill that leaves them so, and count
+    /// towards the cap. Leaves the taker for the caller to finish.
+    fn internal_sweep(&mut self, market: &mut Market, taker: &mut Order, max_matches: u32) {
+        let opposite = taker.side.opposite();
+        let limit = Price::of(taker);
+        let now = now_ms();
+        let mut matches = 0;
+        while matches < max_matches && taker.status == OrderStatus::Open {
+            let (price, level) = match market.book.best(&opposite) {
+                Some(best) => best,
+                None => break,
+            };
+            if !crosses(&taker.side, &limit, &price) {
+                break;
+            }
+            let maker_id = level.head.expect("empty price level");
+            let mut maker = self.orders.get(&maker_id).expect("maker not found");
+            matches += 1;
+            if maker.is_expired(now) {
+                self.internal_expire(market, &mut maker);
+                self.internal_save_order(&maker);
+                continue;
+            }
+            if maker.owner_id == taker.owner_id {
+                let mode = self_trade_mode(market, taker);
+                if mode != SelfTradePrevention::Allow {
+                    self.internal_prevent_self_trade(market, &mut maker, taker, mode);
+                    self.internal_save_order(&maker);
+                    continue;
+                }
+            }
+            let (base_fill, quote_paid) = match match_amounts(&maker, taker, market.lot_size) {
+                Some(amounts) => amounts,
+                // A maker no taker can fill would hold up the whole side
+                None if self.internal_cancel_unfillable(market, &mut maker) => {
+                    self.internal_save_order(&maker);
+                    continue;
+                }
+                None => break,
+            };
+            self.internal_fill(market, &mut maker, taker, base_fill, quote_paid, None);
+            self.internal_cancel_unfillable(market, &mut maker);
+            self.internal_save_order(&maker);
+        }
+    }
+
+    /// Trades `amount_in` of `token_in` straight against the book without
+    /// storing an order: a market sell of base or a quote-sized market buy,
+    /// never filling at a price worse than `amount_in`/`min_out` implies.
+    /// The taker is held by the contract's own account under `SWAP_ORDER_ID`,
+    /// never stored, so the proceeds and any released lock are taken back out
+    /// of its balances. Fails unless at least `min_out` comes out. Returns
+    /// `(amount_out, unused_in)`.
+    pub(crate) fn internal_swap(
+        &mut self,
+        market: &mut Market,
+        token_in: &TokenId,
+        amount_in: u128,
+        min_out: u128,
+        max_matches: u32,
+    ) -> (u128, u128) {
+        assert!(min_out > 0, "min_out must be > 0");
+        let (side, worst_price_num, worst_price_den, token_out) = if *token_in == market.base_token_id {
+            ("sell", min_out, amount_in, market.quote_token_id.clone())
+        } else {
+            ("buy", amount_in, min_out, market.base_token_id.clone())
+        };
+        let escrow_id = env::current_account_id();
+        let request = market_request(side, U128(amount_in), U128(worst_price_num), U128(worst_price_den));
+        let mut taker = Order::new(SWAP_ORDER_ID, market.id, escrow_id.clone(), request);
+
+        let in_before = self.internal_get_balance(&escrow_id, token_in);
+        let out_before = self.internal_get_balance(&escrow_id, &token_out);
+        self.internal_sweep(market, &mut taker, max_matches);
+        let amount_out = self.internal_get_balance(&escrow_id, &token_out) - out_before;
+        let released = self.internal_get_balance(&escrow_id, token_in) - in_before;
+        assert!(amount_out >= min_out, "swap output below min_out");
+        self.internal_sub_balance(&escrow_id, &token_out, amount_out);
+        if released > 0 {
+            self.internal_sub_balance(&escrow_id, token_in, released);
+        }
+        let unused_in = released + taker.locked_quote_remaining.0 + taker.locked_base_remaining.0;
+        (amount_out, unused_in)
+    }
+
+    /// Cancels an open `order` whose remainder can no longer be filled (see
+    /// `fills_a_lot`), refunding its lock. Returns whether it did.
+    pub(crate) fn internal_cancel_unfillable(&mut self, market: &mut Market, order: &mut Order) -> bool {
+        let price = Price::of(order);
+        let (base, locked_quote) = (order.remaining_base.0, order.locked_quote_remaining.0);
+        if order.status != OrderStatus::Open || fills_a_lot(&order.side, base, locked_quote, &price, market.lot_size) {
+            return false;
+        }
+        self.internal_cancel(market, order, "unfillable");
+        true
+    }
+
+    /// Price a new order will rest at. Post-only orders that would cross are
+    /// rejected, or with `PostOnlySlide` moved one step inside the best
+    /// opposite price: one tick in markets with a tick size, otherwise one
+    /// unit at the order's own denominator.
+    pub(crate) fn internal_post_only_price(&self, market: &Market, side: &Side, tif: TimeInForce, price: Price) -> Price {
+        if tif != TimeInForce::PostOnly && tif != TimeInForce::PostOnlySlide {
+            return price;
+        }
+        let best = match market.book.best(&side.opposite()) {
+            Some((best, _)) => best,
+            None => return price,
+        };
+        if !crosses(side, &price, &best) {
+            return price;
+        }
+        assert!(tif == TimeInForce::PostOnlySlide, "post-only order would cross");
+        if let Some(tick) = market.tick_size {
+            // best / tick, in ticks, then one tick inside. Dividing by
+            // `best.den` and `tick.num` in turn rounds the same way as
+            // dividing by their product, which may not fit in u128.
+            let ticks = match side {
+                Side::Buy => mul_div_ceil(best.num, tick.den, best.den).div_ceil(tick.num) - 1,
+                Side::Sell => mul_div_floor(best.num, tick.den, best.den) / tick.num + 1,
+            };
+            assert!(ticks > 0, "post-only order cannot be re-priced");
+            return Price::new(ticks.checked_mul(tick.num).expect("arithmetic overflow"), tick.den);
+        }
+        let num = match side {
+            Side::Buy => mul_div_ceil(best.num, price.den, best.den) - 1,
+            Side::Sell => mul_div_floor(best.num, price.den, best.den) + 1,
+        };
+        assert!(num > 0, "post-only order cannot be re-priced");
+        Price::new(num, price.den)
+    }
+}
+
+/// Whether an order on `side` limited at `limit` reaches a level at `price`.
+pub(crate) fn crosses(side: &Side, limit: &Price, price: &Price) -> bool {
+    match side {
+        Side::Buy => price.cmp_value(limit) != Ordering::Greater,
+        Side::Sell => price.cmp_value(limit) != Ordering::Less,
+    }
+}
+
+/// Whether an order on `side` at `price` with `base` left (and, for a buy,
+/// `locked_quote` to pay the rounded-up quote with) still makes a fill worth
+/// at least one unit of quote: all of `base`, or a whole number of lots.
+pub(crate) fn fills_a_lot(side: &Side, base: u128, locked_quote: u128, price: &Price, lot_size: u128) -> bool {
+    let affordable = match side {
+        Side::Buy => checked_mul_div_floor(locked_quote, price.den, price.num).unwrap_or(u128::MAX),
+        Side::Sell => u128::MAX,
+    };
+    let base = if affordable >= base { base } else { affordable - affordable % lot_size };
+    base > 0 && checked_mul_div_floor(base, price.num, price.den).is_none_or(|quote| quote > 0)
+}
+
+/// Largest fill between a resting maker and a crossing taker at the maker's
+/// price, as `(base_fill, quote_paid)`, in whole lots unless it completes
+/// either order. Quote is rounded in the maker's favour; if that breaks the
+/// taker's limit, the fill is cut down to a base amount that converts to
+/// quote exactly.
+fn match_amounts(maker: &Order, taker: &Order, lot_size: u128) -> Option<(u128, u128)> {
+    let (num, den) = (maker.price_num.0, maker.price_den.0);
+    let buyer = if maker.side == Side::Buy { maker } else { taker };
+    let affordable = checked_mul_div_floor(buyer.locked_quote_remaining.0, den, num).unwrap_or(u128::MAX);
+    let taker_base = if taker.is_quote_sized() { u128::MAX } else { taker.remaining_base.0 };
+    let mut base = maker.remaining_base.0.min(taker_base).min(affordable);
+    // A remainder left under a lot (e.g. by a raised lot size) fills whole
+    if base != maker.remaining_base.0 && base != taker_base {
+        base -= base % lot_size;
+    }
+    let quote_for = |base: u128| match maker.side {
+        Side::Sell => mul_div_ceil(base, num, den),
+        Side::Buy => mul_div_floor(base, num, den),
+    };
+    let mut quote = quote_for(base);
+    let (taker_num, taker_den) = (taker.price_num.0, taker.price_den.0);
+    let within_taker_limit = match taker.side {
+        Side::Buy => mul_cmp(quote, taker_den, base, taker_num) != Ordering::Greater,
+        Side::Sell => mul_cmp(quote, taker_den, base, taker_num) != Ordering::Less,
+    };
+    if !within_taker_limit {
+        let exact = den / gcd(num, den);
+        // Smallest amount both exact and a whole number of lots; none fits if it overflows
+        let step = (exact / gcd(exact, lot_size)).saturating_mul(lot_size);
+        base -= base % step;
+        quote = quote_for(base);
+    }
+    if base == 0 || quote == 0 {
+        return None;
+    }
+    Some((base, quote))
+}
+
+#[cfg(test)]
+mod tests {
+    use near_contract_standards::storage_management::StorageManagement;
+
+    use super::*;
+    use crate::test_utils::{acc, context, new_market, register};
+    use crate::{Contract, MarketId};
+
+    const E18: u128 = 1_000_000_000_000_000_000;
+    const E24: u128 = 1_000_000_000_000_000_000_000_000;
+
+    /// A resting sell by alice at `ask` and a buy by bob at `bid` left
+    /// crossing, each for `amount` base, as
+    /// `(contract, market_id, sell_id, buy_id)`.
+    fn crossing_pair(amount: u128, ask: (u128, u128), bid: (u128, u128)) -> (Contract, MarketId, u64, u64) {
+        let (mut contract, market_id) = new_market();
+        register(&mut contract, "alice.near");
+        register(&mut contract, "bob.near");
+        contract.internal_add_balance(&acc("alice.near"), &acc("base.near"), amount);
+        let spend = mul_div_ceil(amount, bid.0, bid.1);
+        contract.internal_add_balance(&acc("bob.near"), &acc("quote.near"), spend);
+        context("alice.near");
+        let sell = contract.place_order(market_id, "sell".into(), U128(amount), None, U128(ask.0), U128(ask.1), None, None, None, None, None, None);
+        context("bob.near");
+        let buy = contract.place_order(market_id, "buy".into(), U128(amount), Some(U128(spend)), U128(bid.0), U128(bid.1), Some(0), None, None, None, None, None);
+        (contract, market_id, sell, buy)
+    }
+
+    #[test]
+    fn execute_fills_at_18_decimals() {
+        let (mut contract, _, sell, buy) = crossing_pair(5 * E18, (10 * E18, E18), (11 * E18, E18));
+        context("owner.near");
+        contract.execute(sell, buy, U128(5 * E18), U128(50 * E18));
+        assert!(contract.get_order(sell).is_none() && contract.get_order(buy).is_none());
+        assert_eq!(contract.get_balance(acc("bob.near"), acc("base.near")).0, 5 * E18);
+        assert_eq!(contract.get_balance(acc("bob.near"), acc("quote.near")).0, 5 * E18);
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("quote.near")).0, 50 * E18);
+    }
+
+    #[test]
+    #[should_panic(expected = "price below maker's minimum")]
+    fn execute_rejects_fill_below_ask_at_24_decimals() {
+        // Both sides of the check exceed u128; saturated, they compared equal
+        let (mut contract, _, sell, buy) = crossing_pair(5 * E24, (10 * E24 + 1, E24), (11 * E24, E24));
+        context("owner.near");
+        contract.execute(sell, buy, U128(5 * E24), U128(50 * E24));
+    }
+
+    #[test]
+    #[should_panic(expected = "price above taker's maximum")]
+    fn execute_rejects_fill_above_bid_at_24_decimals() {
+        let (mut contract, _, sell, buy) = crossing_pair(5 * E24, (9 * E24, E24), (10 * E24, E24 + 1));
+        context("owner.near");
+        contract.execute(sell, buy, U128(5 * E24), U128(50 * E24));
+    }
+
+    #[test]
+    fn execute_fills_at_24_decimals() {
+        let (mut contract, market_id, sell, buy) = crossing_pair(5 * E24, (10 * E24, E24), (11 * E24, E24));
+        context("owner.near");
+        contract.execute(sell, buy, U128(3 * E24), U128(30 * E24));
+        assert_eq!(contract.get_order(sell).unwrap().remaining_base.0, 2 * E24);
+        let ask = contract.get_best_ask(market_id).unwrap();
+        assert_eq!((ask.price_num.0, ask.price_den.0, ask.total_base.0), (10, 1, 2 * E24));
+    }
+
+    #[test]
+    fn matcher_stats_need_registration() {
+        let (mut contract, _, sell, buy) = crossing_pair(5, (10, 1), (10, 1));
+        context("owner.near");
+        contract.execute(sell, buy, U128(2), U128(20));
+        assert!(contract.get_matcher_stats(acc("owner.near")).is_none());
+        register(&mut contract, "owner.near");
+        context("owner.near");
+        contract.execute(sell, buy, U128(3), U128(30));
+        assert_eq!(contract.get_matcher_stats(acc("owner.near")).unwrap().fills, 1);
+        contract.storage_unregister(None);
+        assert!(contract.get_matcher_stats(acc("owner.near")).is_none());
+    }
+
+    #[test]
+    fn sweep_cancels_a_dust_bid_in_front_of_a_real_bid() {
+        let (mut contract, market_id) = new_market();
+        for who in ["alice.near", "bob.near", "carol.near"] {
+            register(&mut contract, who);
+        }
+        contract.internal_add_balance(&acc("alice.near"), &acc("base.near"), 5);
+        contract.internal_add_balance(&acc("bob.near"), &acc("quote.near"), 50);
+        contract.internal_add_balance(&acc("carol.near"), &acc("quote.near"), 1);
+        // Placed before placement rejected it: 1 quote buys nothing at 10^30
+        let mut market = contract.market(market_id);
+        let request = crate::limit_request("buy", U128(1), Some(U128(1)), U128(E24 * 1_000_000), U128(1), TimeInForce::Gtc);
+        let dust = contract.internal_create_order(&market, acc("carol.near"), request);
+        market.book.insert(&dust);
+        contract.save_market(&market);
+        context("bob.near");
+        contract.place_order(market_id, "buy".into(), U128(5), Some(U128(50)), U128(10), U128(1), None, None, None, None, None, None);
+        context("alice.near");
+        contract.place_order(market_id, "sell".into(), U128(5), None, U128(10), U128(1), None, None, None, None, None, None);
+        assert!(contract.get_order(dust.id).is_none());
+        assert_eq!(contract.get_balance(acc("carol.near"), acc("quote.near")).0, 1);
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("quote.near")).0, 50);
+        assert_eq!(contract.get_balance(acc("bob.near"), acc("base.near")).0, 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "order too small to fill a lot at its price")]
+    fn place_order_rejects_a_buy_whose_lock_buys_no_lot() {
+        let (mut contract, market_id) = new_market();
+        register(&mut contract, "carol.near");
+        contract.internal_add_balance(&acc("carol.near"), &acc("quote.near"), 1);
+        context("carol.near");
+        contract.place_order(market_id, "buy".into(), U128(1), Some(U128(1)), U128(E24 * 1_000_000), U128(1), None, None, None, None, None, None);
+    }
+
+    #[test]
+    fn remainder_under_a_raised_lot_size_still_fills() {
+        let (mut contract, market_id, sell, buy) = crossing_pair(15, (1, 1), (1, 1));
+        context("owner.near");
+        contract.set_market_limits(market_id, None, None, Some(U128(10)), None);
+        contract.execute(sell, buy, U128(10), U128(10));
+        // Only 5 is left of each: a fill completing either needs no whole lot
+        contract.execute(sell, buy, U128(5), U128(5));
+        assert!(contract.get_order(sell).is_none() && contract.get_order(buy).is_none());
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("quote.near")).0, 15);
+        assert_eq!(contract.get_balance(acc("bob.near"), acc("base.near")).0, 15);
+    }
+
+    #[test]
+    fn buy_out_of_lock_for_a_lot_is_cancelled() {
+        let (mut contract, market_id) = new_market();
+        context("owner.near");
+        contract.set_market_limits(market_id, None, None, Some(U128(2)), None);
+        register(&mut contract, "alice.near");
+        register(&mut contract, "bob.near");
+        contract.internal_add_balance(&acc("alice.near"), &acc("base.near"), 4);
+        contract.internal_add_balance(&acc("bob.near"), &acc("quote.near"), 5);
+        context("alice.near");
+        contract.place_order(market_id, "sell".into(), U128(4), None, U128(1), U128(1), None, None, None, None, None, None);
+        // Spends up to 5 on 10 base: 4 fill, then 1 quote buys no lot of 2
+        context("bob.near");
+        let buy = contract.place_order(market_id, "buy".into(), U128(10), Some(U128(5)), U128(1), U128(1), None, None, None, None, None, None);
+        assert!(contract.get_order(buy).is_none());
+        assert!(contract.get_best_bid(market_id).is_none());
+        assert_eq!(contract.get_balance(acc("bob.near"), acc("base.near")).0, 4);
+        assert_eq!(contract.get_balance(acc("bob.near"), acc("quote.near")).0, 1);
+    }
+
+    /// Alice's resting sell of `amount` at `num`/`den`, with bob holding
+    /// 1000 quote, in a market given the tick size `tick` afterwards.
+    fn resting_ask(amount: u128, num: u128, den: u128, tick: Option<(u128, u128)>) -> (Contract, MarketId) {
+        let (mut contract, market_id) = new_market();
+        register(&mut contract, "alice.near");
+        register(&mut contract, "bob.near");
+        contract.internal_add_balance(&acc("alice.near"), &acc("base.near"), amount);
+        contract.internal_add_balance(&acc("bob.near"), &acc("quote.near"), 1_000);
+        context("alice.near");
+        contract.place_order(market_id, "sell".into(), U128(amount), None, U128(num), U128(den), None, None, None, None, None, None);
+        if let Some((tick_num, tick_den)) = tick {
+            context("owner.near");
+            contract.set_market_limits(market_id, Some(U128(tick_num)), Some(U128(tick_den)), None, None);
+        }
+        (contract, market_id)
+    }
+
+    /// Bob's buy of 5 base at `num`/`den` with `time_in_force`.
+    fn bob_buys(contract: &mut Contract, market_id: MarketId, num: u128, den: u128, time_in_force: &str) -> u64 {
+        context("bob.near");
+        let tif = Some(time_in_force.to_string());
+        contract.place_order(market_id, "buy".into(), U128(5), Some(U128(100)), U128(num), U128(den), None, tif, None, None, None, None)
+    }
+
+    #[test]
+    fn ioc_releases_the_unfilled_rest() {
+        let (mut contract, market_id) = resting_ask(3, 10, 1, None);
+        let buy = bob_buys(&mut contract, market_id, 10, 1, "ioc");
+        assert!(contract.get_order(buy).is_none());
+        assert!(contract.get_best_bid(market_id).is_none());
+        assert_eq!(contract.get_balance(acc("bob.near"), acc("base.near")).0, 3);
+        assert_eq!(contract.get_balance(acc("bob.near"), acc("quote.near")).0, 970);
+    }
+
+    #[test]
+    #[should_panic(expected = "fill-or-kill order not fully filled")]
+    fn fok_reverts_on_a_partial_fill() {
+        let (mut contract, market_id) = resting_ask(3, 10, 1, None);
+        bob_buys(&mut contract, market_id, 10, 1, "fok");
+    }
+
+    #[test]
+    #[should_panic(expected = "post-only order would cross")]
+    fn post_only_rejects_a_crossing_order() {
+        let (mut contract, market_id) = resting_ask(3, 10, 1, None);
+        bob_buys(&mut contract, market_id, 10, 1, "post_only");
+    }
+
+    #[test]
+    fn post_only_slide_rests_one_tick_inside_the_best_ask() {
+        // 21/4, placed before the tick, is 10.5 ticks of 1/2: the buy rests
+        // at 10 ticks, 5
+        let (mut contract, market_id) = resting_ask(3, 21, 4, Some((1, 2)));
+        let buy = bob_buys(&mut contract, market_id, 6, 1, "post_only_slide");
+        let bid = contract.get_best_bid(market_id).unwrap();
+        assert_eq!((bid.price_num.0, bid.price_den.0, bid.total_base.0), (5, 1, 5));
+        assert_eq!(contract.get_order(buy).unwrap().status, "open");
+        assert_eq!(contract.get_best_ask(market_id).unwrap().total_base.0, 3);
+    }
+
+    #[test]
+    fn post_only_slide_rests_one_tick_inside_the_best_bid() {
+        let (mut contract, market_id) = new_market();
+        register(&mut contract, "alice.near");
+        register(&mut contract, "bob.near");
+        contract.internal_add_balance(&acc("bob.near"), &acc("quote.near"), 100);
+        contract.internal_add_balance(&acc("alice.near"), &acc("base.near"), 5);
+        bob_buys(&mut contract, market_id, 21, 4, "gtc");
+        context("owner.near");
+        contract.set_market_limits(market_id, Some(U128(1)), Some(U128(2)), None, None);
+        // The bid, placed before the tick, is 10.5 ticks: the sell rests at
+        // 11 ticks, 11/2
+        context("alice.near");
+        let tif = Some("post_only_slide".to_string());
+        contract.place_order(market_id, "sell".into(), U128(5), None, U128(4), U128(1), None, tif, None, None, None, None);
+        let ask = contract.get_best_ask(market_id).unwrap();
+        assert_eq!((ask.price_num.0, ask.price_den.0), (11, 2));
+    }
+
+    #[test]
+    fn post_only_slide_without_a_tick_steps_at_the_orders_denominator() {
+        // 21/4 is 52.5 tenths: the buy at 61/10 rests at 52/10
+        let (mut contract, market_id) = resting_ask(3, 21, 4, None);
+        bob_buys(&mut contract, market_id, 61, 10, "post_only_slide");
+        let bid = contract.get_best_bid(market_id).unwrap();
+        assert_eq!((bid.price_num.0, bid.price_den.0), (26, 5));
+    }
+}
@@ -0,0 +1,110 @@
+use std::cmp::Ordering;
+
+/// Full 256-bit product of two u128 values as `(high, low)` words.
+pub fn mul_wide(a: u128, b: u128) -> (u128, u128) {
+    const MASK: u128 = u64::MAX as u128;
+    let (a_hi, a_lo) = (a >> 64, a & MASK);
+    let (b_hi, b_lo) = (b >> 64, b & MASK);
+    let ll = a_lo * b_lo;
+    let lh = a_lo * b_hi;
+    let hl = a_hi * b_lo;
+    let hh = a_hi * b_hi;
+    let mid = (ll >> 64) + (lh & MASK) + (hl & MASK);
+    let lo = (ll & MASK) | (mid << 64);
+    let hi = hh + (lh >> 64) + (hl >> 64) + (mid >> 64);
+    (hi, lo)
+}
+
+/// Quotient and remainder of the 256-bit `(hi, lo)` divided by `d`, or
+/// `None` when the quotient does not fit in u128.
+pub fn div_wide(hi: u128, lo: u128, d: u128) -> Option<(u128, u128)> {
+    assert!(d > 0, "division by zero");
+    if hi >= d {
+        return None;
+    }
+    // Long division, one bit of `lo` at a time; `rem` stays below `d`
+    let (mut quot, mut rem) = (0u128, hi);
+    for bit in (0..128).rev() {
+        let carry = rem >> 127;
+        rem = (rem << 1) | ((lo >> bit) & 1);
+        quot <<= 1;
+        if carry == 1 || rem >= d {
+            rem = rem.wrapping_sub(d);
+            quot |= 1;
+        }
+    }
+    Some((quot, rem))
+}
+
+/// `a * b / c` rounded down, with a 256-bit intermediate product, or
+/// `None` if the result overflows u128.
+pub fn checked_mul_div_floor(a: u128, b: u128, c: u128) -> Option<u128> {
+    let (hi, lo) = mul_wide(a, b);
+    div_wide(hi, lo, c).map(|(quot, _)| quot)
+}
+
+/// `a * b / c` rounded up, with a 256-bit intermediate product, or `None`
+/// if the result overflows u128.
+pub fn checked_mul_div_ceil(a: u128, b: u128, c: u128) -> Option<u128> {
+    let (hi, lo) = mul_wide(a, b);
+    let (quot, rem) = div_wide(hi, lo, c)?;
+    if rem == 0 { Some(quot) } else { quot.checked_add(1) }
+}
+
+/// `a * b / c` rounded down. Panics if the result overflows u128.
+pub fn mul_div_floor(a: u128, b: u128, c: u128) -> u128 {
+    checked_mul_div_floor(a, b, c).expect("arithmetic overflow")
+}
+
+/// `a * b / c` rounded up. Panics if the result overflows u128.
+pub fn mul_div_ceil(a: u128, b: u128, c: u128) -> u128 {
+    checked_mul_div_ceil(a, b, c).expect("arithmetic overflow")
+}
+
+/// Compares `a * b` with `c * d` exactly.
+pub fn mul_cmp(a: u128, b: u128, c: u128, d: u128) -> Ordering {
+    mul_wide(a, b).cmp(&mul_wide(c, d))
+}
+
+pub fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const E18: u128 = 1_000_000_000_000_000_000;
+    const E24: u128 = 1_000_000_000_000_000_000_000_000;
+
+    #[test]
+    fn mul_div_beyond_u128_products() {
+        // 1e6 tokens of 24 decimals times a 24-decimal price denominator
+        assert_eq!(mul_div_floor(1_000_000 * E24, 3 * E24, 2 * E24), 1_500_000 * E24);
+        assert_eq!(mul_div_floor(10 * E24, 10 * E24 + 1, E24), 100 * E24 + 10);
+        assert_eq!(mul_div_ceil(10 * E24, 10 * E24 + 1, 3 * E24), (100 * E24 + 10).div_ceil(3));
+        assert_eq!(mul_div_floor(u128::MAX, u128::MAX, u128::MAX), u128::MAX);
+        assert_eq!(mul_div_ceil(7 * E18, 5, 3), (35 * E18).div_ceil(3));
+        assert_eq!(checked_mul_div_floor(1_000_000 * E24, E24, 1), None);
+        assert_eq!(checked_mul_div_ceil(u128::MAX, 2, 2), Some(u128::MAX));
+        assert_eq!(checked_mul_div_ceil(u128::MAX, 3, 2), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "arithmetic overflow")]
+    fn mul_div_result_overflow() {
+        mul_div_floor(u128::MAX, 2, 1);
+    }
+
+    #[test]
+    fn mul_cmp_does_not_saturate() {
+        // Both products exceed u128::MAX; saturating_mul would call them equal
+        let (quote, base) = (50 * E24, 5 * E24);
+        assert_eq!(mul_cmp(quote, E24, base, 10 * E24 + 1), Ordering::Less);
+        assert_eq!(mul_cmp(quote, E24, base, 10 * E24), Ordering::Equal);
+        assert_eq!(mul_cmp(50 * E18, E18, 5 * E18, 10 * E18), Ordering::Equal);
+    }
+}
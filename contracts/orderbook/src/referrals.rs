@@ -0,0 +1,226 @@
+use near_sdk::collections::UnorderedSet;
+use near_sdk::json_types::U128;
+use near_sdk::{assert_one_yocto, env, near_bindgen, AccountId};
+
+use crate::matchers::BPS_DENOMINATOR;
+use crate::math::mul_div_floor;
+use crate::storage::{balance_entry_bytes, STORAGE_RECORD_OVERHEAD};
+use crate::{emit_event, Contract, ContractExt, MarketId, Role, StorageKey, TokenId};
+
+/// Upper bound on the referral share: half of every fee a referee pays.
+pub const MAX_REFERRAL_SHARE_BPS: u16 = 5_000;
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the caller's referrer. Allowed once; both accounts must be
+    /// registered and the link is charged to the caller's storage deposit.
+    #[payable]
+    pub fn set_referrer(&mut self, referrer_id: AccountId) {
+        assert_one_yocto();
+        self.internal_set_referrer(&env::predecessor_account_id(), referrer_id);
+    }
+
+    /// Owner or fee manager. `share_bps` of each fee a referee pays is
+    /// credited to its referrer instead of the treasury.
+    #[payable]
+    pub fn set_referral_share(&mut self, share_bps: u16) {
+        assert_one_yocto();
+        self.assert_role(Role::FeeManager);
+        assert!(share_bps <= MAX_REFERRAL_SHARE_BPS, "referral share too high");
+        self.referral_share_bps = share_bps;
+        emit_event(
+            "referral_config",
+            near_sdk::serde_json::json!({
+                "share_bps": share_bps,
+                "by": env::predecessor_account_id(),
+            }),
+        );
+    }
+
+    pub fn get_referral_share(&self) -> u16 {
+        self.referral_share_bps
+    }
+
+    pub fn get_referrer(&self, account_id: AccountId) -> Option<AccountId> {
+        self.referrers.get(&account_id)
+    }
+
+    pub fn get_referees(&self, referrer_id: AccountId, from_index: u64, limit: u64) -> Vec<AccountId> {
+        self.referees
+            .get(&referrer_id)
+            .map(|set| set.iter().skip(from_index as usize).take(limit as usize).collect())
+            .unwrap_or_default()
+    }
+
+    /// Referral credits `referrer_id` has earned, per token.
+    pub fn get_referral_earnings(&self, referrer_id: AccountId) -> Vec<(TokenId, U128)> {
+        self.listed_tokens
+            .iter()
+            .filter_map(|token_id| {
+                let earned = self.referral_earnings.get(&(referrer_id.clone(), token_id.clone()))?;
+                Some((token_id, U128(earned)))
+            })
+            .collect()
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_set_referrer(&mut self, account_id: &AccountId, referrer_id: AccountId) {
+        assert!(self.is_registered(account_id), "account is not registered");
+        assert!(!self.referrers.contains_key(account_id), "referrer already set");
+        assert_ne!(account_id, &referrer_id, "can't refer yourself");
+        assert!(self.is_registered(&referrer_id), "referrer is not registered");
+        let initial_storage = env::storage_usage();
+        self.referrers.insert(account_id, &referrer_id);
+        let mut referees = self.referees_set_for(&referrer_id);
+        referees.insert(account_id);
+        self.referees.insert(&referrer_id, &referees);
+        self.internal_storage_charge(account_id, env::storage_usage() - initial_storage);
+        emit_event(
+            "referrer_set",
+            near_sdk::serde_json::json!({
+                "account_id": account_id,
+                "referrer_id": referrer_id,
+            }),
+        );
+    }
+
+    /// Sets the referrer named in an `ft_on_transfer` msg unless the account
+    /// already has one. An invalid referrer panics, refunding the transfer.
+    pub(crate) fn internal_maybe_set_referrer(&mut self, account_id: &AccountId, referrer_id: Option<AccountId>) {
+        if let Some(referrer_id) = referrer_id {
+            if !self.referrers.contains_key(account_id) {
+                self.internal_set_referrer(account_id, referrer_id);
+            }
+        }
+    }
+
+    fn referees_set_for(&self, referrer_id: &AccountId) -> UnorderedSet<AccountId> {
+        if let Some(set) = self.referees.get(referrer_id) { return set; }
+        let mut prefix = vec![];
+        prefix.extend(b"rf:");
+        prefix.extend(env::sha256(referrer_id.as_bytes()));
+        let bytes = StorageKey::RefereesSet { account_hash: prefix };
+        UnorderedSet::new(near_sdk::borsh::to_vec(&bytes).unwrap())
+    }
+
+    /// Credits the referrer of `payer_id` its share of `fee` and returns
+    /// what is left for the treasury. Referrers that have since
+    /// unregistered earn nothing, and neither do referrers whose deposit
+    /// doesn't cover the balance and earnings entries a first credit in
+    /// `token_id` creates.
+    pub(crate) fn internal_referral_credit(
+        &mut self,
+        market_id: MarketId,
+        payer_id: &AccountId,
+        token_id: &TokenId,
+        fee: u128,
+    ) -> u128 {
+        let referrer_id = match self.referrers.get(payer_id) {
+            Some(referrer_id) if self.is_registered(&referrer_id) => referrer_id,
+            _ => return fee,
+        };
+        let credit = mul_div_floor(fee, self.referral_share_bps as u128, BPS_DENOMINATOR);
+        if credit == 0 {
+            return fee;
+        }
+        let key = (referrer_id.clone(), token_id.clone());
+        let earned = self.referral_earnings.get(&key);
+        let mut new_bytes = 0;
+        if earned.is_none() {
+            new_bytes += earnings_entry_bytes(&key);
+        }
+        if self.internal_get_balance(&referrer_id, token_id) == 0 {
+            new_bytes += balance_entry_bytes(&referrer_id, token_id);
+        }
+        if !self.can_store(&referrer_id, new_bytes) {
+            return fee;
+        }
+        self.internal_add_balance(&referrer_id, token_id, credit);
+        let initial_storage = env::storage_usage();
+        self.referral_earnings.insert(&key, &(earned.unwrap_or(0) + credit));
+        self.internal_storage_charge(&referrer_id, env::storage_usage() - initial_storage);
+        emit_event(
+            "referral_credit",
+            near_sdk::serde_json::json!({
+                "market_id": market_id,
+                "referrer_id": referrer_id,
+                "referee_id": payer_id,
+                "token_id": token_id,
+                "amount": U128(credit),
+            }),
+        );
+        fee - credit
+    }
+
+    /// Drops `account_id`'s own referral link and its earnings as a
+    /// referrer. Its referees keep their links (they paid for them) but earn
+    /// it nothing unless it registers again. Called when the account
+    /// unregisters.
+    pub(crate) fn internal_remove_referrals(&mut self, account_id: &AccountId) {
+        if let Some(referrer_id) = self.referrers.remove(account_id) {
+            if let Some(mut referees) = self.referees.get(&referrer_id) {
+                referees.remove(account_id);
+                if referees.is_empty() {
+                    self.referees.remove(&referrer_id);
+                } else {
+                    self.referees.insert(&referrer_id, &referees);
+                }
+            }
+        }
+        for token_id in self.listed_tokens.to_vec() {
+            self.referral_earnings.remove(&(account_id.clone(), token_id));
+        }
+    }
+}
+
+/// Bytes of a `referral_earnings` entry under `key`.
+fn earnings_entry_bytes(key: &(AccountId, TokenId)) -> u64 {
+    // prefix + borsh((AccountId, TokenId)) key, u128 value
+    STORAGE_RECORD_OVERHEAD + 1 + 4 + key.0.len() as u64 + 4 + key.1.len() as u64 + 16
+}
+
+#[cfg(test)]
+mod tests {
+    use near_contract_standards::storage_management::StorageManagement;
+
+    use super::*;
+    use crate::test_utils::{acc, alice_sells_to_bob, context, new_market, register, register_with_deposit};
+
+    /// Alice, referred by carol, sells 1000 base at 1 to bob with a 100 bps
+    /// maker fee, half of it shared with referrers.
+    fn referred_trade(contract: &mut Contract, market_id: MarketId) {
+        context("owner.near");
+        contract.set_fee_config(100, 0);
+        contract.set_referral_share(5_000);
+        register(contract, "alice.near");
+        register(contract, "bob.near");
+        context("alice.near");
+        contract.set_referrer(acc("carol.near"));
+        alice_sells_to_bob(contract, market_id, None, None);
+    }
+
+    #[test]
+    fn referral_credit_is_charged_to_the_referrer() {
+        let (mut contract, market_id) = new_market();
+        register(&mut contract, "carol.near");
+        let registered = contract.storage_accounts.get(&acc("carol.near")).unwrap().used_bytes;
+        referred_trade(&mut contract, market_id);
+        assert_eq!(contract.get_balance(acc("carol.near"), acc("quote.near")).0, 5);
+        assert_eq!(contract.get_referral_earnings(acc("carol.near")), vec![(acc("quote.near"), U128(5))]);
+        let key = (acc("carol.near"), acc("quote.near"));
+        let charged = balance_entry_bytes(&key.0, &key.1) + earnings_entry_bytes(&key);
+        assert_eq!(contract.storage_accounts.get(&acc("carol.near")).unwrap().used_bytes, registered + charged);
+    }
+
+    #[test]
+    fn referrer_without_storage_leaves_the_fee_to_the_treasury() {
+        let (mut contract, market_id) = new_market();
+        let min = contract.storage_balance_bounds().min;
+        register_with_deposit(&mut contract, "carol.near", min);
+        referred_trade(&mut contract, market_id);
+        assert_eq!(contract.get_balance(acc("carol.near"), acc("quote.near")).0, 0);
+        assert!(contract.get_referral_earnings(acc("carol.near")).is_empty());
+        assert_eq!(contract.get_treasury_balances(), vec![(acc("quote.near"), U128(10))]);
+    }
+}
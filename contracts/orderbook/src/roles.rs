@@ -0,0 +1,154 @@
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::collections::UnorderedSet;
+use near_sdk::{assert_one_yocto, env, near_bindgen, AccountId};
+
+use crate::{emit_event, Contract, ContractExt, StorageKey};
+
+/// Permissions the owner hands out. The owner passes every role check.
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, PartialEq, Debug)]
+#[borsh(crate = "near_sdk::borsh")]
+pub enum Role {
+    /// Lists and configures markets.
+    Operator,
+    /// Pauses and unpauses trading, including emergency mode.
+    Pauser,
+    /// Changes fee settings.
+    FeeManager,
+    /// Submits `execute` fills.
+    Matcher,
+}
+
+fn parse_role(s: &str) -> Role {
+    match s.to_ascii_lowercase().as_str() {
+        "operator" => Role::Operator,
+        "pauser" => Role::Pauser,
+        "fee_manager" => Role::FeeManager,
+        "matcher" => Role::Matcher,
+        _ => env::panic_str("invalid role"),
+    }
+}
+
+fn role_str(role: Role) -> &'static str {
+    match role {
+        Role::Operator => "operator",
+        Role::Pauser => "pauser",
+        Role::FeeManager => "fee_manager",
+        Role::Matcher => "matcher",
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    #[payable]
+    pub fn grant_role(&mut self, role: String, account_id: AccountId) {
+        self.assert_owner();
+        let role = parse_role(&role);
+        let mut members = self.role_members_for(role);
+        if members.insert(&account_id) {
+            self.role_members.insert(&role, &members);
+            self.internal_emit_role_event("role_grant", role, &account_id);
+        }
+    }
+
+    #[payable]
+    pub fn revoke_role(&mut self, role: String, account_id: AccountId) {
+        self.assert_owner();
+        self.internal_revoke_role(parse_role(&role), &account_id);
+    }
+
+    /// Gives up one of the caller's own roles.
+    #[payable]
+    pub fn renounce_role(&mut self, role: String) {
+        assert_one_yocto();
+        self.internal_revoke_role(parse_role(&role), &env::predecessor_account_id());
+    }
+
+    /// Explicit membership only; the owner is not listed but passes every check.
+    pub fn has_role(&self, role: String, account_id: AccountId) -> bool {
+        self.role_members.get(&parse_role(&role)).is_some_and(|members| members.contains(&account_id))
+    }
+
+    pub fn get_role_members(&self, role: String) -> Vec<AccountId> {
+        self.role_members.get(&parse_role(&role)).map_or(vec![], |members| members.to_vec())
+    }
+}
+
+impl Contract {
+    fn role_members_for(&self, role: Role) -> UnorderedSet<AccountId> {
+        self.role_members.get(&role).unwrap_or_else(|| UnorderedSet::new(StorageKey::RoleMembersSet { role }))
+    }
+
+    /// Panics unless the caller is the owner or holds `role`.
+    pub(crate) fn assert_role(&self, role: Role) {
+        let caller = env::predecessor_account_id();
+        if caller == self.owner_id {
+            return;
+        }
+        let allowed = self.role_members.get(&role).is_some_and(|members| members.contains(&caller));
+        assert!(allowed, "Requires {} role", role_str(role));
+    }
+
+    fn internal_revoke_role(&mut self, role: Role, account_id: &AccountId) {
+        let mut members = self.role_members_for(role);
+        if members.remove(account_id) {
+            self.role_members.insert(&role, &members);
+            self.internal_emit_role_event("role_revoke", role, account_id);
+        }
+    }
+
+    fn internal_emit_role_event(&self, event: &'static str, role: Role, account_id: &AccountId) {
+        emit_event(
+            event,
+            near_sdk::serde_json::json!({
+                "role": role_str(role),
+                "account_id": account_id,
+                "by": env::predecessor_account_id(),
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{acc, context, new_market};
+
+    #[test]
+    #[should_panic(expected = "Requires operator role")]
+    fn non_holders_are_rejected() {
+        let (mut contract, _) = new_market();
+        context("carol.near");
+        contract.add_market(acc("wrap.near"), acc("quote.near"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only owner")]
+    fn only_the_owner_grants_roles() {
+        let (mut contract, _) = new_market();
+        context("owner.near");
+        contract.grant_role("operator".into(), acc("carol.near"));
+        context("carol.near");
+        contract.grant_role("operator".into(), acc("dave.near"));
+    }
+
+    #[test]
+    fn granted_operator_lists_markets() {
+        let (mut contract, _) = new_market();
+        context("owner.near");
+        contract.grant_role("operator".into(), acc("carol.near"));
+        assert!(contract.has_role("operator".into(), acc("carol.near")));
+        context("carol.near");
+        assert_eq!(contract.add_market(acc("wrap.near"), acc("quote.near")), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires operator role")]
+    fn revoked_role_is_rejected() {
+        let (mut contract, _) = new_market();
+        context("owner.near");
+        contract.grant_role("operator".into(), acc("carol.near"));
+        contract.revoke_role("operator".into(), acc("carol.near"));
+        assert!(contract.get_role_members("operator".into()).is_empty());
+        context("carol.near");
+        contract.add_market(acc("wrap.near"), acc("quote.near"));
+    }
+}
@@ -0,0 +1,232 @@
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{assert_one_yocto, env, near_bindgen};
+
+use crate::math::mul_div_floor;
+use crate::{emit_event, Contract, ContractExt, Market, MarketId, Order, Role, Side};
+
+/// What happens when an order would trade against another order of the same
+/// owner. The taker's own policy applies, falling back to its market's.
+/// Newest and oldest are by order id.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[borsh(crate = "near_sdk::borsh")]
+#[serde(crate = "near_sdk::serde")]
+pub enum SelfTradePrevention {
+    /// Trade as with any other owner.
+    Allow,
+    CancelNewest,
+    CancelOldest,
+    CancelBoth,
+    /// Shrink both orders by the smaller one's size without trading; the
+    /// smaller one (or both, when equal) is cancelled.
+    DecrementAndCancel,
+}
+
+pub(crate) fn parse_self_trade_prevention(s: &str) -> SelfTradePrevention {
+    match s.to_ascii_lowercase().as_str() {
+        "allow" => SelfTradePrevention::Allow,
+        "cancel_newest" => SelfTradePrevention::CancelNewest,
+        "cancel_oldest" => SelfTradePrevention::CancelOldest,
+        "cancel_both" => SelfTradePrevention::CancelBoth,
+        "decrement_and_cancel" => SelfTradePrevention::DecrementAndCancel,
+        _ => env::panic_str("invalid self-trade prevention mode"),
+    }
+}
+
+pub(crate) fn self_trade_prevention_str(mode: SelfTradePrevention) -> &'static str {
+    match mode {
+        SelfTradePrevention::Allow => "allow",
+        SelfTradePrevention::CancelNewest => "cancel_newest",
+        SelfTradePrevention::CancelOldest => "cancel_oldest",
+        SelfTradePrevention::CancelBoth => "cancel_both",
+        SelfTradePrevention::DecrementAndCancel => "decrement_and_cancel",
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Owner or operator. Policy for orders of `market_id` placed without
+    /// their own.
+    #[payable]
+    pub fn set_self_trade_prevention(&mut self, market_id: MarketId, mode: String) {
+        assert_one_yocto();
+        self.assert_role(Role::Operator);
+        let mut market = self.market(market_id);
+        market.self_trade_prevention = parse_self_trade_prevention(&mode);
+        self.save_market(&market);
+        emit_event(
+            "self_trade_prevention_config",
+            near_sdk::serde_json::json!({
+                "market_id": market_id,
+                "mode": self_trade_prevention_str(market.self_trade_prevention),
+                "by": env::predecessor_account_id(),
+            }),
+        );
+    }
+}
+
+/// Policy for `taker` crossing an order of its own owner.
+pub(crate) fn self_trade_mode(market: &Market, taker: &Order) -> SelfTradePrevention {
+    taker.self_trade_prevention.unwrap_or(market.self_trade_prevention)
+}
+
+impl Contract {
+    /// Applies `mode` to two crossing orders of the same owner instead of
+    /// filling them, and emits `self_trade_prevented`. Callers persist both.
+    pub(crate) fn internal_prevent_self_trade(
+        &mut self,
+        market: &mut Market,
+        maker: &mut Order,
+        taker: &mut Order,
+        mode: SelfTradePrevention,
+    ) {
+        let maker_is_newest = maker.id > taker.id;
+        let mut cancelled = vec![];
+        let mut decremented = 0;
+        let (cancel_maker, cancel_taker) = match mode {
+            SelfTradePrevention::Allow => return,
+            SelfTradePrevention::CancelNewest => (maker_is_newest, !maker_is_newest),
+            SelfTradePrevention::CancelOldest => (!maker_is_newest, maker_is_newest),
+            SelfTradePrevention::CancelBoth => (true, true),
+            SelfTradePrevention::DecrementAndCancel => {
+                let (maker_size, taker_size) = (maker.base_capacity(), taker.base_capacity());
+                decremented = maker_size.min(taker_size);
+                if maker_size > decremented {
+                    self.internal_decrement(market, maker, decremented);
+                }
+                if taker_size > decremented {
+                    self.internal_decrement(market, taker, decremented);
+                }
+                (maker_size == decremented, taker_size == decremented)
+            }
+        };
+        if cancel_maker {
+            self.internal_cancel(market, maker, "self_trade");
+            cancelled.push(maker.id);
+        }
+        if cancel_taker {
+            self.internal_cancel(market, taker, "self_trade");
+            cancelled.push(taker.id);
+        }
+        emit_event(
+            "self_trade_prevented",
+            near_sdk::serde_json::json!({
+                "market_id": market.id,
+                "owner_id": taker.owner_id,
+                "maker_order_id": maker.id,
+                "taker_order_id": taker.id,
+                "mode": self_trade_prevention_str(mode),
+                "cancelled": cancelled,
+                "decremented": U128(decremented),
+            }),
+        );
+    }
+
+    /// Shrinks an order by `base` it can no longer trade, returning the
+    /// matching share of its lock to the owner.
+    fn internal_decrement(&mut self, market: &mut Market, order: &mut Order, base: u128) {
+        match order.side {
+            Side::Sell => {
+                order.remaining_base = U128(order.remaining_base.0 - base);
+                order.locked_base_remaining = U128(order.locked_base_remaining.0 - base);
+                self.internal_add_balance(&order.owner_id, &market.base_token_id, base);
+            }
+            Side::Buy => {
+                let release = if order.is_quote_sized() {
+                    mul_div_floor(base, order.price_num.0, order.price_den.0)
+                } else {
+                    let release = mul_div_floor(order.locked_quote_remaining.0, base, order.remaining_base.0);
+                    order.remaining_base = U128(order.remaining_base.0 - base);
+                    release
+                };
+                order.locked_quote_remaining = U128(order.locked_quote_remaining.0 - release);
+                self.internal_add_balance(&order.owner_id, &market.quote_token_id, release);
+            }
+        }
+        market.book.fill(order, base);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{acc, context, new_market, register};
+
+    /// Meets alice's resting sell of 10 at 1 (order 0) with her buy of 6 at
+    /// 1 (order 1) under `mode`, through `execute` or by sweeping the book
+    /// when the buy is placed. Checks what is left open of each order and
+    /// alice's rolling volume, and that none of her funds went missing.
+    fn assert_self_trade(mode: &str, sweep: bool, left: (Option<u128>, Option<u128>), volume: u128) {
+        let (mut contract, market_id) = new_market();
+        register(&mut contract, "alice.near");
+        contract.internal_add_balance(&acc("alice.near"), &acc("base.near"), 10);
+        contract.internal_add_balance(&acc("alice.near"), &acc("quote.near"), 6);
+        context("alice.near");
+        contract.place_order(market_id, "sell".into(), U128(10), None, U128(1), U128(1), None, None, None, None, None, None);
+        let max_matches = if sweep { None } else { Some(0) };
+        contract.place_order(market_id, "buy".into(), U128(6), Some(U128(6)), U128(1), U128(1), max_matches, None, None, None, None, Some(mode.into()));
+        if !sweep {
+            context("owner.near");
+            contract.execute(0, 1, U128(6), U128(6));
+        }
+        let (sell, buy) = (contract.get_order(0), contract.get_order(1));
+        assert_eq!((sell.as_ref().map(|o| o.remaining_base.0), buy.as_ref().map(|o| o.remaining_base.0)), left);
+        assert_eq!(contract.get_account_fee_tier(acc("alice.near"), market_id).volume.0, volume);
+        let base = contract.get_balance(acc("alice.near"), acc("base.near")).0;
+        let quote = contract.get_balance(acc("alice.near"), acc("quote.near")).0;
+        assert_eq!(base + sell.map_or(0, |o| o.locked_base_remaining.0), 10);
+        assert_eq!(quote + buy.map_or(0, |o| o.locked_quote_remaining.0), 6);
+    }
+
+    #[test]
+    fn allow_counts_self_trade_volume_once_in_execute() {
+        assert_self_trade("allow", false, (Some(4), None), 6);
+    }
+
+    #[test]
+    fn allow_counts_self_trade_volume_once_in_sweep() {
+        assert_self_trade("allow", true, (Some(4), None), 6);
+    }
+
+    #[test]
+    fn cancel_newest_in_execute() {
+        assert_self_trade("cancel_newest", false, (Some(10), None), 0);
+    }
+
+    #[test]
+    fn cancel_newest_in_sweep() {
+        assert_self_trade("cancel_newest", true, (Some(10), None), 0);
+    }
+
+    #[test]
+    fn cancel_oldest_in_execute() {
+        assert_self_trade("cancel_oldest", false, (None, Some(6)), 0);
+    }
+
+    #[test]
+    fn cancel_oldest_in_sweep() {
+        // The buy sweeps on past the cancelled sell and rests
+        assert_self_trade("cancel_oldest", true, (None, Some(6)), 0);
+    }
+
+    #[test]
+    fn cancel_both_in_execute() {
+        assert_self_trade("cancel_both", false, (None, None), 0);
+    }
+
+    #[test]
+    fn cancel_both_in_sweep() {
+        assert_self_trade("cancel_both", true, (None, None), 0);
+    }
+
+    #[test]
+    fn decrement_and_cancel_in_execute() {
+        assert_self_trade("decrement_and_cancel", false, (Some(4), None), 0);
+    }
+
+    #[test]
+    fn decrement_and_cancel_in_sweep() {
+        assert_self_trade("decrement_and_cancel", true, (Some(4), None), 0);
+    }
+}
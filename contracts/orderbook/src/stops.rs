@@ -0,0 +1,313 @@
+use std::cmp::Ordering;
+
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::collections::TreeMap;
+use near_sdk::json_types::U128;
+use near_sdk::{assert_one_yocto, env, near_bindgen, IntoStorageKey};
+
+use crate::book::Price;
+use crate::matching::DEFAULT_MAX_MATCHES;
+use crate::{
+    emit_event, limit_request, market_request, Contract, ContractExt, Market, MarketId, Order, OrderStatus, Side,
+    TimeInForce,
+};
+
+/// Default cap on stop orders activated by one `trigger_stops` call.
+pub const DEFAULT_MAX_TRIGGERS: u32 = 8;
+
+/// Pending stop orders keyed by `(trigger price, order id)`. Buy stops fire
+/// once the last trade is at or above their trigger, sell stops at or below.
+#[derive(BorshDeserialize, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct StopBook {
+    buys: TreeMap<(Price, u64), ()>,
+    sells: TreeMap<(Price, u64), ()>,
+}
+
+impl StopBook {
+    pub fn new<S: IntoStorageKey>(buys: S, sells: S) -> Self {
+        Self { buys: TreeMap::new(buys), sells: TreeMap::new(sells) }
+    }
+
+    fn key(order: &Order) -> (Price, u64) {
+        (order.trigger_price.expect("not a stop order"), order.id)
+    }
+
+    pub fn insert(&mut self, order: &Order) {
+        match order.side {
+            Side::Buy => self.buys.insert(&Self::key(order), &()),
+            Side::Sell => self.sells.insert(&Self::key(order), &()),
+        };
+    }
+
+    pub fn remove(&mut self, order: &Order) {
+        match order.side {
+            Side::Buy => self.buys.remove(&Self::key(order)),
+            Side::Sell => self.sells.remove(&Self::key(order)),
+        };
+    }
+
+    /// A pending stop whose trigger `last` has reached, if any.
+    pub fn next_triggered(&self, last: &Price) -> Option<u64> {
+        if let Some((trigger, id)) = self.buys.min() {
+            if trigger.cmp_value(last) != Ordering::Greater {
+                return Some(id);
+            }
+        }
+        if let Some((trigger, id)) = self.sells.max() {
+            if trigger.cmp_value(last) != Ordering::Less {
+                return Some(id);
+            }
+        }
+        None
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Limit order that stays pending, with its funds locked, until the last
+    /// trade price reaches `trigger_price`.
+    #[payable]
+    #[allow(clippy::too_many_arguments)]
+    pub fn place_stop_limit_order(
+        &mut self,
+        market_id: MarketId,
+        side: String,
+        amount_base: U128,
+        max_spend_quote: Option<U128>,
+        price_num: U128,
+        price_den: U128,
+        trigger_price_num: U128,
+        trigger_price_den: U128,
+    ) -> u64 {
+        assert_one_yocto();
+        self.assert_running();
+        let mut market = self.market(market_id);
+        let mut request = limit_request(&side, amount_base, max_spend_quote, price_num, price_den, TimeInForce::Gtc);
+        request.trigger_price = Some(check_trigger(&market, &request.side, trigger_price_num, trigger_price_den));
+        market.assert_order_size(&request);
+        let order = self.internal_create_order(&market, env::predecessor_account_id(), request);
+        market.stops.insert(&order);
+        self.save_market(&market);
+        order.id
+    }
+
+    /// Market order (see `place_market_order`) that stays pending, with its
+    /// funds locked, until the last trade price reaches `trigger_price`.
+    #[payable]
+    #[allow(clippy::too_many_arguments)]
+    pub fn place_stop_market_order(
+        &mut self,
+        market_id: MarketId,
+        side: String,
+        amount: U128,
+        worst_price_num: U128,
+        worst_price_den: U128,
+        trigger_price_num: U128,
+        trigger_price_den: U128,
+    ) -> u64 {
+        assert_one_yocto();
+        self.assert_running();
+        let mut market = self.market(market_id);
+        let mut request = market_request(&side, amount, worst_price_num, worst_price_den);
+        request.trigger_price = Some(check_trigger(&market, &request.side, trigger_price_num, trigger_price_den));
+        let order = self.internal_create_order(&market, env::predecessor_account_id(), request);
+        market.stops.insert(&order);
+        self.save_market(&market);
+        order.id
+    }
+
+    /// Permissionless keeper entry point: activates up to `limit` stop orders
+    /// of a market whose trigger its last trade price has reached and returns
+    /// their ids. Fills made by activated orders move the last price and can
+    /// cascade.
+    pub fn trigger_stops(&mut self, market_id: MarketId, limit: Option<u32>) -> Vec<u64> {
+        self.assert_running();
+        let limit = limit.unwrap_or(DEFAULT_MAX_TRIGGERS) as usize;
+        let mut market = self.market(market_id);
+        let mut triggered = vec![];
+        while triggered.len() < limit {
+            let last = match market.last_trade_price {
+                Some(last) => last,
+                None => break,
+            };
+            let order_id = match market.stops.next_triggered(&last) {
+                Some(order_id) => order_id,
+                None => break,
+            };
+            self.internal_trigger_stop(&mut market, order_id, &last);
+            triggered.push(order_id);
+        }
+        self.save_market(&market);
+        triggered
+    }
+
+    pub fn get_last_trade_price(&self, market_id: MarketId) -> Option<(U128, U128)> {
+        self.market(market_id).last_trade_price.map(|p| (U128(p.num), U128(p.den)))
+    }
+}
+
+/// Rejects a stop that the market's last trade price would fire at once.
+fn check_trigger(market: &Market, side: &Side, num: U128, den: U128) -> Price {
+    assert!(num.0 > 0 && den.0 > 0, "trigger price must be positive");
+    let trigger = Price::new(num.0, den.0);
+    if let Some(last) = market.last_trade_price {
+        let fires = match side {
+            Side::Buy => trigger.cmp_value(&last) != Ordering::Greater,
+            Side::Sell => trigger.cmp_value(&last) != Ordering::Less,
+        };
+        assert!(!fires, "stop would trigger immediately");
+    }
+    trigger
+}
+
+impl Contract {
+    fn internal_trigger_stop(&mut self, market: &mut Market, order_id: u64, last: &Price) {
+        let mut order = self.orders.get(&order_id).expect("Order not found");
+        market.stops.remove(&order);
+        order.status = OrderStatus::Open;
+        let trigger = order.trigger_price.expect("not a stop order");
+        emit_event(
+            "order_triggered",
+            near_sdk::serde_json::json!({
+                "order_id": order.id,
+                "owner_id": order.owner_id,
+                "trigger_price_num": U128(trigger.num),
+                "trigger_price_den": U128(trigger.den),
+                "last_price_num": U128(last.num),
+                "last_price_den": U128(last.den),
+            }),
+        );
+        self.internal_match_order(market, &mut order, DEFAULT_MAX_MATCHES);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{acc, context, new_market, register};
+
+    /// A market whose last trade was at 10, with alice and bob funded to
+    /// trade and carol holding 100 quote and 10 base for stops.
+    fn traded_market() -> (Contract, MarketId) {
+        let (mut contract, market_id) = new_market();
+        for who in ["alice.near", "bob.near", "carol.near"] {
+            register(&mut contract, who);
+        }
+        contract.internal_add_balance(&acc("alice.near"), &acc("base.near"), 100);
+        contract.internal_add_balance(&acc("bob.near"), &acc("quote.near"), 1_000);
+        contract.internal_add_balance(&acc("carol.near"), &acc("quote.near"), 100);
+        contract.internal_add_balance(&acc("carol.near"), &acc("base.near"), 10);
+        trade_at(&mut contract, market_id, 10);
+        (contract, market_id)
+    }
+
+    /// Alice sells bob one base at `price`.
+    fn trade_at(contract: &mut Contract, market_id: MarketId, price: u128) {
+        context("alice.near");
+        contract.place_order(market_id, "sell".into(), U128(1), None, U128(price), U128(1), None, None, None, None, None, None);
+        context("bob.near");
+        contract.place_order(market_id, "buy".into(), U128(1), Some(U128(price)), U128(price), U128(1), None, None, None, None, None, None);
+    }
+
+    /// Carol's stop-limit buy of one base at `price`, triggered at `trigger`.
+    fn stop_buy(contract: &mut Contract, market_id: MarketId, price: u128, trigger: u128) -> u64 {
+        context("carol.near");
+        contract.place_stop_limit_order(market_id, "buy".into(), U128(1), Some(U128(price)), U128(price), U128(1), U128(trigger), U128(1))
+    }
+
+    fn balance(contract: &Contract, who: &str, token: &str) -> u128 {
+        contract.get_balance(acc(who), acc(token)).0
+    }
+
+    #[test]
+    fn stops_lock_funds_at_placement() {
+        let (mut contract, market_id) = traded_market();
+        let buy = stop_buy(&mut contract, market_id, 12, 11);
+        context("carol.near");
+        let sell = contract.place_stop_market_order(market_id, "sell".into(), U128(4), U128(8), U128(1), U128(9), U128(1));
+        assert_eq!(balance(&contract, "carol.near", "quote.near"), 88);
+        assert_eq!(balance(&contract, "carol.near", "base.near"), 6);
+        for id in [buy, sell] {
+            assert_eq!(contract.get_order(id).unwrap().status, "pending");
+        }
+        assert!(contract.get_best_bid(market_id).is_none() && contract.get_best_ask(market_id).is_none());
+    }
+
+    #[test]
+    fn buy_stop_triggers_at_or_above_its_price() {
+        let (mut contract, market_id) = traded_market();
+        let buy = stop_buy(&mut contract, market_id, 12, 11);
+        assert!(contract.trigger_stops(market_id, None).is_empty());
+        trade_at(&mut contract, market_id, 11);
+        assert_eq!(contract.trigger_stops(market_id, None), vec![buy]);
+        assert_eq!(contract.get_order(buy).unwrap().status, "open");
+        assert_eq!(contract.get_best_bid(market_id).unwrap().price_num.0, 12);
+    }
+
+    #[test]
+    fn sell_stop_triggers_at_or_below_its_price() {
+        let (mut contract, market_id) = traded_market();
+        context("carol.near");
+        let sell = contract.place_stop_limit_order(market_id, "sell".into(), U128(1), None, U128(8), U128(1), U128(9), U128(1));
+        trade_at(&mut contract, market_id, 11);
+        assert!(contract.trigger_stops(market_id, None).is_empty());
+        trade_at(&mut contract, market_id, 9);
+        assert_eq!(contract.trigger_stops(market_id, None), vec![sell]);
+        assert_eq!(contract.get_best_ask(market_id).unwrap().price_num.0, 8);
+    }
+
+    #[test]
+    #[should_panic(expected = "stop would trigger immediately")]
+    fn buy_stop_at_the_last_price_is_rejected() {
+        let (mut contract, market_id) = traded_market();
+        stop_buy(&mut contract, market_id, 12, 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "stop would trigger immediately")]
+    fn sell_stop_above_the_last_price_is_rejected() {
+        let (mut contract, market_id) = traded_market();
+        context("carol.near");
+        contract.place_stop_limit_order(market_id, "sell".into(), U128(1), None, U128(8), U128(1), U128(11), U128(1));
+    }
+
+    #[test]
+    fn triggered_fills_cascade() {
+        let (mut contract, market_id) = traded_market();
+        context("alice.near");
+        for price in [12, 13] {
+            contract.place_order(market_id, "sell".into(), U128(1), None, U128(price), U128(1), None, None, None, None, None, None);
+        }
+        let first = stop_buy(&mut contract, market_id, 12, 11);
+        let second = stop_buy(&mut contract, market_id, 13, 12);
+        trade_at(&mut contract, market_id, 11);
+        // The first stop's fill at 12 reaches the second's trigger
+        assert_eq!(contract.trigger_stops(market_id, None), vec![first, second]);
+        assert_eq!(balance(&contract, "carol.near", "base.near"), 12);
+        assert_eq!(contract.get_last_trade_price(market_id), Some((U128(13), U128(1))));
+        assert!(contract.get_best_ask(market_id).is_none());
+    }
+
+    #[test]
+    fn trigger_stops_respects_its_limit() {
+        let (mut contract, market_id) = traded_market();
+        let first = stop_buy(&mut contract, market_id, 11, 11);
+        let second = stop_buy(&mut contract, market_id, 11, 11);
+        trade_at(&mut contract, market_id, 11);
+        assert_eq!(contract.trigger_stops(market_id, Some(1)), vec![first]);
+        assert_eq!(contract.trigger_stops(market_id, Some(1)), vec![second]);
+    }
+
+    #[test]
+    fn cancelling_a_pending_stop_returns_its_lock() {
+        let (mut contract, market_id) = traded_market();
+        let buy = stop_buy(&mut contract, market_id, 12, 11);
+        context("carol.near");
+        contract.cancel_order(buy);
+        assert!(contract.get_order(buy).is_none());
+        assert_eq!(balance(&contract, "carol.near", "quote.near"), 100);
+        trade_at(&mut contract, market_id, 11);
+        assert!(contract.trigger_stops(market_id, None).is_empty());
+    }
+}
@@ -0,0 +1,321 @@
+use near_contract_standards::fungible_token::Balance;
+use near_contract_standards::storage_management::{StorageBalance, StorageBalanceBounds, StorageManagement};
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::{assert_one_yocto, env, near_bindgen, AccountId, NearToken, Promise};
+
+use crate::{emit_event, BalanceKey, Contract, ContractExt, TokenId};
+
+/// Bytes the runtime charges for every storage record on top of its key and value.
+pub(crate) const STORAGE_RECORD_OVERHEAD: u64 = 40;
+/// Longest valid account id, used to size the per-account reservation.
+const MAX_ACCOUNT_ID_LEN: u64 = 64;
+/// Allowance for an order's entry in the book or stop book: its queue link
+/// plus a new price level or stop index node.
+pub(crate) const ORDER_BOOK_ENTRY_BYTES: u64 = 320;
+
+/// NEP-145 deposit of one account and the bytes charged against it.
+#[derive(BorshDeserialize, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct StorageAccount {
+    pub deposit: Balance,
+    pub used_bytes: u64,
+}
+
+fn storage_cost(bytes: u64) -> Balance {
+    env::storage_byte_cost().as_yoctonear() * bytes as Balance
+}
+
+/// Bytes of `account_id`'s `balances` entry for `token_id`.
+pub(crate) fn balance_entry_bytes(account_id: &AccountId, token_id: &TokenId) -> u64 {
+    let key = BalanceKey { account_id: account_id.clone(), token_id: token_id.clone() };
+    // prefix + borsh(Vec<u8>) of borsh(BalanceKey) key, u128 value
+    STORAGE_RECORD_OVERHEAD + 1 + 4 + near_sdk::borsh::to_vec(&key).unwrap().len() as u64 + 16
+}
+
+#[near_bindgen]
+impl StorageManagement for Contract {
+    /// Registers `account_id` (the caller by default) or tops up its deposit.
+    /// With `registration_only`, anything above the minimum is refunded.
+    #[payable]
+    fn storage_deposit(&mut self, account_id: Option<AccountId>, registration_only: Option<bool>) -> StorageBalance {
+        let amount = env::attached_deposit().as_yoctonear();
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let registration_only = registration_only.unwrap_or(false);
+        let refund = match self.storage_accounts.get(&account_id) {
+            Some(mut account) => {
+                if registration_only {
+                    amount
+                } else {
+                    account.deposit += amount;
+                    self.storage_accounts.insert(&account_id, &account);
+                    0
+                }
+            }
+            None => {
+                let min = self.storage_balance_bounds().min.as_yoctonear();
+                assert!(amount >= min, "deposit is less than the minimum storage balance");
+                let deposit = if registration_only { min } else { amount };
+                let used_bytes = self.account_storage_bytes() + self.unregistered_balance_bytes(&account_id);
+                let account = StorageAccount { deposit, used_bytes };
+                self.storage_accounts.insert(&account_id, &account);
+                amount - deposit
+            }
+        };
+        if refund > 0 {
+            Promise::new(env::predecessor_account_id()).transfer(NearToken::from_yoctonear(refund)).detach();
+        }
+        self.storage_balance_of(account_id).unwrap()
+    }
+
+    /// Withdraws `amount` (all of it by default) of the caller's deposit not
+    /// covering stored data.
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<NearToken>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let balance = self.storage_balance_of(account_id.clone()).expect("account is not registered");
+        let available = balance.available.as_yoctonear();
+        let amount = amount.map_or(available, |a| a.as_yoctonear());
+        assert!(amount <= available, "amount exceeds available storage balance");
+        if amount > 0 {
+            let mut account = self.storage_accounts.get(&account_id).unwrap();
+            account.deposit -= amount;
+            self.storage_accounts.insert(&account_id, &account);
+            Promise::new(account_id.clone()).transfer(NearToken::from_yoctonear(amount)).detach();
+        }
+        self.storage_balance_of(account_id).unwrap()
+    }
+
+    /// Removes the caller's account, its closed orders record, its traded
+    /// volume, its matcher stats, its referral link and earnings and (with
+    /// `force`) any remaining token balances, and returns the whole deposit.
+    /// Accounts with open or pending orders cannot unregister.
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let account = match self.storage_accounts.get(&account_id) {
+            Some(account) => account,
+            None => return false,
+        };
+        assert!(!self.orders_by_owner.contains_key(&account_id), "can't unregister the account with open orders");
+        let force = force.unwrap_or(false);
+        let mut burned = vec![];
+        for token_id in self.listed_tokens.to_vec() {
+            self.account_volumes.remove(&(account_id.clone(), token_id.clone()));
+            let balance = self.internal_get_balance(&account_id, &token_id);
+            if balance > 0 {
+                assert!(force, "can't unregister the account with a positive balance without force");
+                self.internal_sub_balance(&account_id, &token_id, balance);
+                burned.push((token_id, balance));
+            }
+        }
+        self.internal_remove_referrals(&account_id);
+        self.closed_orders.remove(&account_id);
+        self.matcher_stats.remove(&account_id);
+        self.storage_accounts.remove(&account_id);
+        Promise::new(account_id.clone()).transfer(NearToken::from_yoctonear(account.deposit)).detach();
+        emit_event(
+            "storage_unregister",
+            near_sdk::serde_json::json!({
+                "account_id": account_id,
+                "refunded": account.deposit.to_string(),
+                "burned": burned.iter().map(|(token_id, amount)| near_sdk::serde_json::json!({
+                    "token_id": token_id,
+                    "amount": amount.to_string(),
+                })).collect::<Vec<_>>(),
+            }),
+        );
+        true
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: NearToken::from_yoctonear(storage_cost(self.account_storage_bytes())),
+            max: None,
+        }
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_accounts.get(&account_id).map(|account| StorageBalance {
+            total: NearToken::from_yoctonear(account.deposit),
+            available: NearToken::from_yoctonear(account.deposit.saturating_sub(storage_cost(account.used_bytes))),
+        })
+    }
+}
+
+impl Contract {
+    /// Bytes reserved on registration: the account's own record, sized for
+    /// the longest account id. Token balances and traded volume are charged
+    /// as they are created.
+    pub(crate) fn account_storage_bytes(&self) -> u64 {
+        // prefix + borsh(AccountId) key, `StorageAccount` value
+        STORAGE_RECORD_OVERHEAD + 1 + 4 + MAX_ACCOUNT_ID_LEN + 16 + 8
+    }
+
+    /// Bytes of the balances `account_id` already holds when it registers:
+    /// balances migrated from before storage management, or a withdrawal
+    /// refunded after it unregistered. They are charged from then on.
+    fn unregistered_balance_bytes(&self, account_id: &AccountId) -> u64 {
+        self.listed_tokens
+            .iter()
+            .filter(|token_id| self.internal_get_balance(account_id, token_id) > 0)
+            .map(|token_id| balance_entry_bytes(account_id, &token_id))
+            .sum()
+    }
+
+    pub(crate) fn is_registered(&self, account_id: &AccountId) -> bool {
+        self.storage_accounts.contains_key(account_id)
+    }
+
+    /// Charges `bytes` against `account_id`'s storage deposit.
+    pub(crate) fn internal_storage_charge(&mut self, account_id: &AccountId, bytes: u64) {
+        let mut account = self.storage_accounts.get(account_id).expect("account is not registered");
+        account.used_bytes += bytes;
+        self.storage_accounts.insert(account_id, &account);
+        self.assert_storage_covered(account_id);
+    }
+
+    /// Charges `bytes` against `account_id`'s storage deposit even past what
+    /// it covers, for data the account can't refuse, like balances created
+    /// by its fills. Until it tops up, it can't add data of its own or
+    /// withdraw storage. Unregistered accounts (the contract's swap escrow,
+    /// or a refund racing `storage_unregister`) are not charged.
+    pub(crate) fn internal_storage_force_charge(&mut self, account_id: &AccountId, bytes: u64) {
+        if let Some(mut account) = self.storage_accounts.get(account_id) {
+            account.used_bytes += bytes;
+            self.storage_accounts.insert(account_id, &account);
+        }
+    }
+
+    /// Whether `account_id` is registered and its deposit covers `bytes`
+    /// more than it is charged for.
+    pub(crate) fn can_store(&self, account_id: &AccountId, bytes: u64) -> bool {
+        self.storage_accounts
+            .get(account_id)
+            .is_some_and(|account| account.deposit >= storage_cost(account.used_bytes + bytes))
+    }
+
+    /// Panics unless `account_id`'s deposit covers every byte charged to it.
+    pub(crate) fn assert_storage_covered(&self, account_id: &AccountId) {
+        let account = self.storage_accounts.get(account_id).expect("account is not registered");
+        assert!(
+            account.deposit >= storage_cost(account.used_bytes),
+            "insufficient storage deposit, call storage_deposit"
+        );
+    }
+
+    /// Gives back `bytes` previously charged to `account_id`.
+    pub(crate) fn internal_storage_release(&mut self, account_id: &AccountId, bytes: u64) {
+        if let Some(mut account) = self.storage_accounts.get(account_id) {
+            account.used_bytes = account.used_bytes.saturating_sub(bytes);
+            self.storage_accounts.insert(account_id, &account);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+    use near_sdk::json_types::U128;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+
+    use super::*;
+    use crate::test_utils::{acc, context, new_market, register, register_with_deposit};
+
+    fn used_bytes(contract: &Contract, who: &str) -> u64 {
+        contract.storage_accounts.get(&acc(who)).unwrap().used_bytes
+    }
+
+    fn balance_bytes(who: &str, token: &str) -> u64 {
+        balance_entry_bytes(&acc(who), &acc(token))
+    }
+
+    #[test]
+    fn balances_are_charged_while_they_exist() {
+        let (mut contract, market_id) = new_market();
+        register(&mut contract, "alice.near");
+        register(&mut contract, "bob.near");
+        let registered = used_bytes(&contract, "alice.near");
+        context("base.near");
+        contract.ft_on_transfer(acc("alice.near"), U128(10), String::new()).detach();
+        assert_eq!(used_bytes(&contract, "alice.near"), registered + balance_bytes("alice.near", "base.near"));
+        context("quote.near");
+        contract.ft_on_transfer(acc("bob.near"), U128(30), String::new()).detach();
+        context("alice.near");
+        contract.place_order(market_id, "sell".into(), U128(10), None, U128(3), U128(1), None, None, None, None, None, None);
+        context("bob.near");
+        contract.place_order(market_id, "buy".into(), U128(10), Some(U128(30)), U128(3), U128(1), None, None, None, None, None, None);
+        // The fill left alice a quote balance instead of a base one
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("quote.near")).0, 30);
+        let filled = used_bytes(&contract, "alice.near");
+        context("alice.near");
+        contract.withdraw(acc("quote.near"), U128(30), None, None).detach();
+        assert_eq!(used_bytes(&contract, "alice.near"), filled - balance_bytes("alice.near", "quote.near"));
+    }
+
+    /// Bytes of bob's quote.near volume history holding `days` days.
+    fn volume_bytes(days: u64) -> u64 {
+        // prefix + borsh((AccountId, TokenId)) key, `Vec<DailyVolume>` value
+        let key_len = 4 + "bob.near".len() as u64 + 4 + "quote.near".len() as u64;
+        STORAGE_RECORD_OVERHEAD + 1 + key_len + 4 + days * (8 + 16)
+    }
+
+    #[test]
+    fn volume_history_is_charged_as_it_grows() {
+        let (mut contract, _) = new_market();
+        register(&mut contract, "bob.near");
+        let registered = used_bytes(&contract, "bob.near");
+        let key = (acc("bob.near"), acc("quote.near"));
+        contract.internal_record_volume(&key.0, &key.1, 30);
+        assert_eq!(used_bytes(&contract, "bob.near"), registered + volume_bytes(1));
+        contract.internal_record_volume(&key.0, &key.1, 30);
+        assert_eq!(used_bytes(&contract, "bob.near"), registered + volume_bytes(1));
+        testing_env!(VMContextBuilder::new()
+            .current_account_id(acc("ob.near"))
+            .block_timestamp(86_400 * 1_000_000_000)
+            .build());
+        contract.internal_record_volume(&key.0, &key.1, 30);
+        assert_eq!(used_bytes(&contract, "bob.near"), registered + volume_bytes(2));
+        assert_eq!(contract.get_account_fee_tier(key.0, 0).volume.0, 90);
+    }
+
+    #[test]
+    #[should_panic(expected = "insufficient storage deposit")]
+    fn deposit_needs_storage_for_a_new_balance() {
+        let (mut contract, _) = new_market();
+        let min = contract.storage_balance_bounds().min;
+        register_with_deposit(&mut contract, "alice.near", min);
+        context("base.near");
+        contract.ft_on_transfer(acc("alice.near"), U128(10), String::new()).detach();
+    }
+
+    #[test]
+    fn fill_closing_an_order_past_its_owners_deposit_succeeds() {
+        let (mut contract, market_id) = new_market();
+        register(&mut contract, "alice.near");
+        register(&mut contract, "bob.near");
+        contract.internal_add_balance(&acc("alice.near"), &acc("base.near"), 10);
+        contract.internal_add_balance(&acc("bob.near"), &acc("quote.near"), 30);
+        context("alice.near");
+        let sell = contract.place_order(market_id, "sell".into(), U128(10), None, U128(3), U128(1), None, None, None, None, None, None);
+        contract.storage_withdraw(None);
+        context("bob.near");
+        contract.place_order(market_id, "buy".into(), U128(10), Some(U128(30)), U128(3), U128(1), None, None, None, None, None, None);
+        assert!(contract.get_order(sell).is_none());
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("quote.near")).0, 30);
+    }
+
+    #[test]
+    fn deposit_tops_up_a_held_balance_past_the_deposit() {
+        let (mut contract, _) = new_market();
+        register(&mut contract, "alice.near");
+        contract.internal_add_balance(&acc("alice.near"), &acc("quote.near"), 30);
+        // As fills crediting her can leave her
+        contract.internal_storage_force_charge(&acc("alice.near"), 100_000);
+        context("quote.near");
+        contract.ft_on_transfer(acc("alice.near"), U128(5), String::new()).detach();
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("quote.near")).0, 35);
+    }
+}
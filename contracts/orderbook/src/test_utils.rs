@@ -0,0 +1,68 @@
+//! Helpers shared by the unit tests.
+
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::storage_management::StorageManagement;
+use near_sdk::json_types::U128;
+use near_sdk::test_utils::VMContextBuilder;
+use near_sdk::{testing_env, AccountId, NearToken};
+
+use crate::{Contract, MarketId};
+
+pub fn acc(s: &str) -> AccountId {
+    s.parse().unwrap()
+}
+
+/// Calls into `ob.near` as `predecessor` with 1 yocto attached.
+pub fn context(predecessor: &str) {
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(acc("ob.near"))
+        .predecessor_account_id(acc(predecessor))
+        .attached_deposit(NearToken::from_yoctonear(1))
+        .build());
+}
+
+/// Registers `who` with a 0.1 NEAR storage deposit.
+pub fn register(contract: &mut Contract, who: &str) {
+    register_with_deposit(contract, who, NearToken::from_millinear(100));
+}
+
+/// Registers `who` with a `storage_deposit` of `amount`.
+pub fn register_with_deposit(contract: &mut Contract, who: &str, amount: NearToken) {
+    testing_env!(VMContextBuilder::new()
+        .current_account_id(acc("ob.near"))
+        .predecessor_account_id(acc(who))
+        .attached_deposit(amount)
+        .build());
+    contract.storage_deposit(None, None);
+}
+
+/// A contract owned by `owner.near` listing `base.near`/`quote.near`.
+pub fn new_market() -> (Contract, MarketId) {
+    context("ob.near");
+    let mut contract = Contract::new(acc("owner.near"));
+    context("owner.near");
+    let market_id = contract.add_market(acc("base.near"), acc("quote.near"));
+    (contract, market_id)
+}
+
+/// Deposits `amount` of `token` for `who` through `ft_on_transfer`.
+pub fn deposit(contract: &mut Contract, who: &str, token: &str, amount: u128) {
+    context(token);
+    contract.ft_on_transfer(acc(who), U128(amount), String::new()).detach();
+}
+
+/// Registered alice and bob each deposit 1000; alice sells all her base at 1
+/// (through `integrator_id` if given) and bob buys it.
+pub fn alice_sells_to_bob(
+    contract: &mut Contract,
+    market_id: MarketId,
+    integrator_id: Option<AccountId>,
+    integrator_fee_bps: Option<u16>,
+) {
+    deposit(contract, "alice.near", "base.near", 1_000);
+    deposit(contract, "bob.near", "quote.near", 1_000);
+    context("alice.near");
+    contract.place_order(market_id, "sell".into(), U128(1_000), None, U128(1), U128(1), None, None, None, integrator_id, integrator_fee_bps, None);
+    context("bob.near");
+    contract.place_order(market_id, "buy".into(), U128(1_000), Some(U128(1_000)), U128(1), U128(1), None, None, None, None, None, None);
+}
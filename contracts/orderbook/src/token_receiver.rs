@@ -0,0 +1,307 @@
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_sdk::json_types::U128;
+use near_sdk::serde::Deserialize;
+use near_sdk::{env, near_bindgen, AccountId, PromiseError, PromiseOrValue};
+
+use crate::storage::balance_entry_bytes;
+use crate::{
+    checked_expiry, emit_event, ft_transfer, limit_request, parse_self_trade_prevention, parse_side,
+    parse_time_in_force, Contract, ContractExt, MarketId, Side, TimeInForce, TokenId, DEFAULT_MAX_MATCHES,
+    GAS_FOR_RESOLVE_WITHDRAW,
+};
+
+/// `ft_on_transfer` msg, e.g. `{"action":"deposit","beneficiary":"bob.near"}`.
+/// An empty msg is a plain deposit for the sender. Deposits and orders may
+/// name a `referrer_id`, set for the credited account unless it already has
+/// one.
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde", tag = "action", rename_all = "snake_case")]
+enum TokenReceiverMsg {
+    /// Credits the transfer to `beneficiary` (the sender by default).
+    Deposit { beneficiary: Option<AccountId>, referrer_id: Option<AccountId> },
+    /// Deposits and places a limit order in `market_id` for the sender
+    /// funded by the transfer: a sell of the base received (or `amount_base`
+    /// of it), or a buy of `amount_base` spending up to the quote received.
+    PlaceOrder {
+        market_id: MarketId,
+        side: String,
+        amount_base: Option<U128>,
+        price_num: U128,
+        price_den: U128,
+        max_matches: Option<u32>,
+        time_in_force: Option<String>,
+        expires_at: Option<u64>,
+        referrer_id: Option<AccountId>,
+        integrator_id: Option<AccountId>,
+        integrator_fee_bps: Option<u16>,
+        self_trade_prevention: Option<String>,
+    },
+    /// Trades the transfer against the book without keeping a balance: the
+    /// proceeds are sent to `receiver_id` (the sender by default) and the
+    /// input not traded is returned to the sender.
+    Swap {
+        market_id: MarketId,
+        min_out: U128,
+        receiver_id: Option<AccountId>,
+        max_matches: Option<u32>,
+    },
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    /// Refunds the whole transfer when no market lists the token, the msg is
+    /// malformed or the account credited is not registered. A swap returns
+    /// the part of the transfer it did not trade.
+    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128> {
+        // Only accept deposits of tokens some market trades
+        let token_id = env::predecessor_account_id();
+        if !self.listed_tokens.contains(&token_id) {
+            return PromiseOrValue::Value(amount);
+        }
+        if self.is_emergency() {
+            return PromiseOrValue::Value(amount);
+        }
+        let action = if msg.is_empty() {
+            TokenReceiverMsg::Deposit { beneficiary: None, referrer_id: None }
+        } else {
+            match near_sdk::serde_json::from_str(&msg) {
+                Ok(action) => action,
+                Err(_) => return PromiseOrValue::Value(amount),
+            }
+        };
+        match action {
+            TokenReceiverMsg::Deposit { beneficiary, referrer_id } => {
+                let account_id = beneficiary.unwrap_or_else(|| sender_id.clone());
+                if !self.is_registered(&account_id) {
+                    return PromiseOrValue::Value(amount);
+                }
+                self.internal_maybe_set_referrer(&account_id, referrer_id);
+                self.internal_deposit(&account_id, &token_id, amount.0, &sender_id);
+            }
+            TokenReceiverMsg::PlaceOrder {
+                market_id,
+                side,
+                amount_base,
+                price_num,
+                price_den,
+                max_matches,
+                time_in_force,
+                expires_at,
+                referrer_id,
+                integrator_id,
+                integrator_fee_bps,
+                self_trade_prevention,
+            } => {
+                if !self.is_registered(&sender_id) {
+                    return PromiseOrValue::Value(amount);
+                }
+                // Failures from here on panic, so the token refunds the transfer.
+                self.assert_running();
+                let mut market = self.market(market_id);
+                let (amount_base, max_spend_quote) = match parse_side(&side) {
+                    Side::Buy => {
+                        assert_eq!(token_id, market.quote_token_id, "buy orders are funded with the quote token");
+                        (amount_base.expect("amount_base required for Buy"), Some(amount))
+                    }
+                    Side::Sell => {
+                        assert_eq!(token_id, market.base_token_id, "sell orders are funded with the base token");
+                        let amount_base = amount_base.unwrap_or(amount);
+                        assert!(amount_base.0 <= amount.0, "amount_base exceeds the transferred amount");
+                        (amount_base, None)
+                    }
+                };
+                let tif = time_in_force.map_or(TimeInForce::Gtc, |t| parse_time_in_force(&t));
+                let mut request = limit_request(&side, amount_base, max_spend_quote, price_num, price_den, tif);
+                request.expires_at = expires_at.map(checked_expiry);
+                (request.integrator_id, request.integrator_fee_bps) =
+                    self.checked_integrator(integrator_id, integrator_fee_bps);
+                request.self_trade_prevention = self_trade_prevention.map(|m| parse_self_trade_prevention(&m));
+                self.internal_maybe_set_referrer(&sender_id, referrer_id);
+                self.internal_deposit(&sender_id, &token_id, amount.0, &sender_id);
+                self.internal_place_order(&mut market, sender_id, request, max_matches);
+                self.save_market(&market);
+            }
+            TokenReceiverMsg::Swap { market_id, min_out, receiver_id, max_matches } => {
+                self.assert_running();
+                let mut market = self.market(market_id);
+                let token_out = if token_id == market.base_token_id {
+                    market.quote_token_id.clone()
+                } else {
+                    assert_eq!(token_id, market.quote_token_id, "token is not traded in this market");
+                    market.base_token_id.clone()
+                };
+                let receiver_id = receiver_id.unwrap_or_else(|| sender_id.clone());
+                let max_matches = max_matches.unwrap_or(DEFAULT_MAX_MATCHES);
+                let (amount_out, unused) =
+                    self.internal_swap(&mut market, &token_id, amount.0, min_out.0, max_matches);
+                self.save_market(&market);
+                ft_transfer(&token_out, &receiver_id, amount_out)
+                    .then(
+                        Self::ext(env::current_account_id())
+                            .with_static_gas(GAS_FOR_RESOLVE_WITHDRAW)
+                            .resolve_swap(sender_id.clone(), receiver_id.clone(), token_out.clone(), U128(amount_out)),
+                    )
+                    .detach();
+                emit_event(
+                    "swap",
+                    near_sdk::serde_json::json!({
+                        "market_id": market_id,
+                        "sender_id": sender_id,
+                        "receiver_id": receiver_id,
+                        "token_in": token_id,
+                        "amount_in": amount,
+                        "token_out": token_out,
+                        "amount_out": U128(amount_out),
+                        "unused": U128(unused),
+                    }),
+                );
+                return PromiseOrValue::Value(U128(unused));
+            }
+        }
+        PromiseOrValue::Value(U128(0))
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Settles a swap's proceeds transfer. A failed transfer is credited to
+    /// the receiver's balance if it is registered, otherwise to the sender's.
+    /// Returns the amount actually sent.
+    #[private]
+    pub fn resolve_swap(&mut self, sender_id: AccountId, receiver_id: AccountId, token_id: TokenId, amount: U128) -> U128 {
+        // `ft_transfer` returns nothing; only a failed call is credited.
+        if !matches!(env::promise_result_checked(0, 0), Err(PromiseError::Failed)) {
+            return amount;
+        }
+        let account_id = if self.is_registered(&receiver_id) { receiver_id.clone() } else { sender_id.clone() };
+        self.internal_add_balance(&account_id, &token_id, amount.0);
+        emit_event(
+            "swap_failed",
+            near_sdk::serde_json::json!({
+                "sender_id": sender_id,
+                "receiver_id": receiver_id,
+                "token_id": token_id,
+                "amount": amount,
+                "credited_to": account_id,
+            }),
+        );
+        U128(0)
+    }
+}
+
+impl Contract {
+    /// Credits a deposit. Only a new balance entry needs the account's
+    /// deposit to cover it; topping up a balance works even past it.
+    fn internal_deposit(&mut self, account_id: &AccountId, token_id: &TokenId, amount: u128, sender_id: &AccountId) {
+        if self.internal_get_balance(account_id, token_id) == 0 {
+            assert!(
+                self.can_store(account_id, balance_entry_bytes(account_id, token_id)),
+                "insufficient storage deposit, call storage_deposit"
+            );
+        }
+        self.internal_add_balance(account_id, token_id, amount);
+        let mut event = near_sdk::serde_json::json!({
+            "account_id": account_id,
+            "token_id": token_id,
+            "amount": amount.to_string(),
+        });
+        if sender_id != account_id {
+            event.as_object_mut().unwrap().insert("sender_id".into(), near_sdk::serde_json::json!(sender_id));
+        }
+        emit_event("deposit", event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_sdk::test_utils::{get_logs, VMContextBuilder};
+    use near_sdk::{test_vm_config, testing_env, PromiseResult, RuntimeFeesConfig};
+
+    use super::*;
+    use crate::test_utils::{acc, context, deposit, new_market, register};
+
+    /// Resolves a swap of 100 quote.near from alice to erin whose transfer
+    /// failed.
+    fn fail_swap_transfer(contract: &mut Contract) -> U128 {
+        let context = VMContextBuilder::new()
+            .current_account_id(acc("ob.near"))
+            .predecessor_account_id(acc("ob.near"))
+            .build();
+        testing_env!(context, test_vm_config(), RuntimeFeesConfig::test(), Default::default(), vec![PromiseResult::Failed]);
+        contract.resolve_swap(acc("alice.near"), acc("erin.near"), acc("quote.near"), U128(100))
+    }
+
+    #[test]
+    fn failed_swap_payout_goes_to_the_sender_unless_the_receiver_is_registered() {
+        let (mut contract, _) = new_market();
+        assert_eq!(fail_swap_transfer(&mut contract).0, 0);
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("quote.near")).0, 100);
+        assert_eq!(contract.get_balance(acc("erin.near"), acc("quote.near")).0, 0);
+        assert!(get_logs()[0].contains("\"swap_failed\""));
+        register(&mut contract, "erin.near");
+        fail_swap_transfer(&mut contract);
+        assert_eq!(contract.get_balance(acc("erin.near"), acc("quote.near")).0, 100);
+    }
+
+    /// The part of a transfer `ft_on_transfer` hands back to the token.
+    fn refunded(result: PromiseOrValue<U128>) -> u128 {
+        match result {
+            PromiseOrValue::Value(amount) => amount.0,
+            PromiseOrValue::Promise(_) => panic!("expected a value"),
+        }
+    }
+
+    #[test]
+    fn unusable_transfers_are_refunded_in_full() {
+        let (mut contract, _) = new_market();
+        register(&mut contract, "alice.near");
+        context("quote.near");
+        assert_eq!(refunded(contract.ft_on_transfer(acc("alice.near"), U128(50), "{not json".into())), 50);
+        let msg = r#"{"action":"deposit","beneficiary":"erin.near"}"#;
+        assert_eq!(refunded(contract.ft_on_transfer(acc("alice.near"), U128(50), msg.into())), 50);
+        let msg = r#"{"action":"place_order","market_id":0,"side":"buy","amount_base":"5","price_num":"1","price_den":"1"}"#;
+        assert_eq!(refunded(contract.ft_on_transfer(acc("erin.near"), U128(50), msg.into())), 50);
+        context("other.near");
+        assert_eq!(refunded(contract.ft_on_transfer(acc("alice.near"), U128(50), String::new())), 50);
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("quote.near")).0, 0);
+        assert!(contract.get_orders_by_owner(acc("erin.near")).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "sell orders are funded with the base token")]
+    fn order_funded_with_the_wrong_token_is_rejected() {
+        let (mut contract, _) = new_market();
+        register(&mut contract, "alice.near");
+        context("quote.near");
+        let msg = r#"{"action":"place_order","market_id":0,"side":"sell","price_num":"1","price_den":"1"}"#;
+        contract.ft_on_transfer(acc("alice.near"), U128(10), msg.into()).detach();
+    }
+
+    #[test]
+    fn sell_of_part_of_the_transfer_keeps_the_rest() {
+        let (mut contract, _) = new_market();
+        register(&mut contract, "alice.near");
+        context("base.near");
+        let msg = r#"{"action":"place_order","market_id":0,"side":"sell","amount_base":"6","price_num":"1","price_den":"1"}"#;
+        assert_eq!(refunded(contract.ft_on_transfer(acc("alice.near"), U128(10), msg.into())), 0);
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("base.near")).0, 4);
+        let order = contract.get_order(0).unwrap();
+        assert_eq!(order.locked_base_remaining.0, 6);
+        assert_eq!(order.remaining_base.0, 6);
+    }
+
+    #[test]
+    fn swap_takes_no_order_id() {
+        let (mut contract, market_id) = new_market();
+        register(&mut contract, "alice.near");
+        deposit(&mut contract, "alice.near", "base.near", 10);
+        context("alice.near");
+        contract.place_order(market_id, "sell".into(), U128(10), None, U128(1), U128(1), None, None, None, None, None, None);
+        context("quote.near");
+        let msg = r#"{"action":"swap","market_id":0,"min_out":"5"}"#;
+        contract.ft_on_transfer(acc("dave.near"), U128(5), msg.into()).detach();
+        let fill = get_logs().into_iter().find(|log| log.contains(r#""event":"order_fill""#)).unwrap();
+        assert!(fill.contains(r#""taker_order_id":null"#) && fill.contains(r#""swap":true"#));
+        assert_eq!(contract.next_order_id, 1);
+    }
+}
@@ -0,0 +1,375 @@
+use near_contract_standards::fungible_token::Balance;
+use near_sdk::borsh::{BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
+use near_sdk::json_types::U128;
+use near_sdk::{env, near_bindgen, AccountId, Gas, NearToken, Promise};
+
+use crate::{
+    emit_event, Contract, ContractExt, ContractStatus, Market, MatcherMode, Order, OrderStatus, OrderType, Price, Side,
+    StorageKey, TimeInForce, TokenId,
+};
+
+/// Storage key of the state layout version, kept next to `STATE`.
+const VERSION_KEY: &[u8] = b"VERSION";
+/// Layout `Contract` is currently stored in.
+pub const STATE_VERSION: u8 = 1;
+
+const GAS_FOR_MIGRATE: Gas = Gas::from_tgas(100);
+
+/// Order as stored before stops, expiry and time in force existed.
+#[derive(BorshDeserialize, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct OrderV0 {
+    pub id: u64,
+    pub owner_id: AccountId,
+    pub side: Side,
+    pub price_num: U128,
+    pub price_den: U128,
+    pub amount_base: U128,
+    pub remaining_base: U128,
+    pub locked_quote_remaining: U128,
+    pub locked_base_remaining: U128,
+    pub status: OrderStatus,
+    pub created_at: u64,
+}
+
+/// The original flat layout: balances and an order list, no book.
+#[derive(BorshDeserialize, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+pub struct ContractV0 {
+    pub base_token_id: TokenId,
+    pub quote_token_id: TokenId,
+    pub balances: LookupMap<Vec<u8>, Balance>,
+    pub orders: UnorderedMap<u64, OrderV0>,
+    pub orders_by_owner: LookupMap<AccountId, UnorderedSet<u64>>,
+    pub next_order_id: u64,
+}
+
+/// Every layout the contract state has been deployed with. The state is
+/// written untagged as `Contract`; the version stored under `VERSION_KEY`
+/// (absent for `V0`) says which variant the bytes decode to.
+#[derive(BorshDeserialize, BorshSerialize)]
+#[borsh(crate = "near_sdk::borsh")]
+#[allow(clippy::large_enum_variant)]
+pub enum VersionedContract {
+    V0(ContractV0),
+    V1(Contract),
+}
+
+impl VersionedContract {
+    fn read() -> Self {
+        let version = env::storage_read(VERSION_KEY).map_or(0, |v| v[0]);
+        let state = env::storage_read(b"STATE").expect("contract is not initialized");
+        match version {
+            0 => Self::V0(decode(&state)),
+            1 => Self::V1(decode(&state)),
+            _ => env::panic_str("unknown state version"),
+        }
+    }
+
+    fn version(&self) -> u8 {
+        match self {
+            Self::V0(_) => 0,
+            Self::V1(_) => 1,
+        }
+    }
+
+    /// Upgrades one layout at a time until the current one.
+    fn into_current(self) -> Contract {
+        let mut state = self;
+        loop {
+            state = match state {
+                Self::V0(v0) => Self::V1(Contract::from_v0(v0)),
+                Self::V1(current) => return current,
+            }
+        }
+    }
+}
+
+fn decode<T: BorshDeserialize>(state: &[u8]) -> T {
+    near_sdk::borsh::from_slice(state).unwrap_or_else(|_| env::panic_str("Cannot deserialize the contract state."))
+}
+
+pub(crate) fn write_state_version() {
+    env::storage_write(VERSION_KEY, &[STATE_VERSION]);
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Loads the state in whatever layout is deployed and converts it to the
+    /// current one. Runs after `upgrade` deploys new code.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let state = VersionedContract::read();
+        emit_event(
+            "migrate",
+            near_sdk::serde_json::json!({
+                "old_version": state.version(),
+                "new_version": STATE_VERSION,
+            }),
+        );
+        let contract = state.into_current();
+        write_state_version();
+        contract
+    }
+
+    /// Deploys the wasm passed as the raw call input and runs `migrate`.
+    #[payable]
+    pub fn upgrade(&mut self) -> Promise {
+        self.assert_owner();
+        let code = env::input().expect("code required");
+        Promise::new(env::current_account_id())
+            .deploy_contract(code)
+            .function_call("migrate".to_string(), vec![], NearToken::from_yoctonear(0), GAS_FOR_MIGRATE)
+    }
+
+    pub fn get_state_version(&self) -> u8 {
+        STATE_VERSION
+    }
+}
+
+impl Contract {
+    /// The single pair becomes market 0. Open orders are re-encoded as GTC
+    /// limit orders of that market at their reduced price and queued in its
+    /// book by id; closed ones are dropped. Existing balances carry over;
+    /// their owners register for storage before placing new orders. The
+    /// contract account that runs the migration becomes the owner, with no
+    /// roles granted, whitelisted matching and no fees.
+    fn from_v0(mut state: ContractV0) -> Self {
+        let mut legacy = state.orders.to_vec();
+        legacy.sort_by_key(|(id, _)| *id);
+        state.orders.clear();
+
+        let mut market = Market::new(0, state.base_token_id, state.quote_token_id);
+        let mut listed_tokens = UnorderedSet::new(StorageKey::ListedTokens);
+        listed_tokens.insert(&market.base_token_id);
+        listed_tokens.insert(&market.quote_token_id);
+        let mut orders = UnorderedMap::new(StorageKey::Orders);
+        let mut orders_by_owner = state.orders_by_owner;
+        for (id, old) in legacy {
+            if old.status != OrderStatus::Open {
+                if let Some(mut set) = orders_by_owner.get(&old.owner_id) {
+                    set.remove(&id);
+                    if set.is_empty() {
+                        orders_by_owner.remove(&old.owner_id);
+                    } else {
+                        orders_by_owner.insert(&old.owner_id, &set);
+                    }
+                }
+                continue;
+            }
+            let price = Price::new(old.price_num.0, old.price_den.0);
+            let order = Order {
+                id,
+                market_id: market.id,
+                owner_id: old.owner_id,
+                side: old.side,
+                price_num: U128(price.num),
+                price_den: U128(price.den),
+                amount_base: old.amount_base,
+                remaining_base: old.remaining_base,
+                locked_quote_remaining: old.locked_quote_remaining,
+                locked_base_remaining: old.locked_base_remaining,
+                status: OrderStatus::Open,
+                created_at: old.created_at,
+                time_in_force: TimeInForce::Gtc,
+                order_type: OrderType::Limit,
+                trigger_price: None,
+                expires_at: None,
+                integrator_id: None,
+                integrator_fee_bps: 0,
+                self_trade_prevention: None,
+                storage_charged: false,
+            };
+            market.book.insert(&order);
+            orders.insert(&id, &order);
+        }
+        let mut markets = UnorderedMap::new(StorageKey::Markets);
+        markets.insert(&market.id, &market);
+
+        Self {
+            owner_id: env::current_account_id(),
+            status: ContractStatus::Running,
+            role_members: LookupMap::new(StorageKey::RoleMembers),
+            matcher_mode: MatcherMode::Whitelist,
+            keeper_reward_bps: 0,
+            matcher_stats: LookupMap::new(StorageKey::MatcherStats),
+            maker_fee_bps: 0,
+            taker_fee_bps: 0,
+            treasury: UnorderedMap::new(StorageKey::Treasury),
+            fee_tiers: vec![],
+            account_volumes: LookupMap::new(StorageKey::AccountVolumes),
+            max_integrator_fee_bps: 0,
+            referral_share_bps: 0,
+            referrers: LookupMap::new(StorageKey::Referrers),
+            referees: LookupMap::new(StorageKey::Referees),
+            referral_earnings: LookupMap::new(StorageKey::ReferralEarnings),
+            markets,
+            next_market_id: 1,
+            listed_tokens,
+            balances: state.balances,
+            orders,
+            orders_by_owner,
+            closed_orders: LookupMap::new(StorageKey::ClosedOrders),
+            next_order_id: state.next_order_id,
+            storage_accounts: LookupMap::new(StorageKey::StorageAccounts),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_contract_standards::storage_management::StorageManagement;
+
+    use super::*;
+    use crate::test_utils::{acc, context, new_market, register, register_with_deposit};
+    use crate::BalanceKey;
+
+    fn write_state<T: BorshSerialize>(state: &T) {
+        env::storage_write(b"STATE", &near_sdk::borsh::to_vec(state).unwrap());
+    }
+
+    fn legacy_order(id: u64, owner: &str, side: Side, amount: u128, price: u128, status: OrderStatus) -> OrderV0 {
+        let (locked_quote, locked_base) = match side {
+            Side::Buy => (amount * price, 0),
+            Side::Sell => (0, amount),
+        };
+        OrderV0 {
+            id,
+            owner_id: acc(owner),
+            side,
+            price_num: U128(price),
+            price_den: U128(1),
+            amount_base: U128(amount),
+            remaining_base: U128(amount),
+            locked_quote_remaining: U128(locked_quote),
+            locked_base_remaining: U128(locked_base),
+            status,
+            created_at: 0,
+        }
+    }
+
+    /// Writes a V0 state with orders 0-2 open and 3 cancelled.
+    fn write_v0_state() {
+        let mut state = ContractV0 {
+            base_token_id: acc("base.near"),
+            quote_token_id: acc("quote.near"),
+            balances: LookupMap::new(StorageKey::Balances),
+            orders: UnorderedMap::new(StorageKey::Orders),
+            orders_by_owner: LookupMap::new(StorageKey::OrdersByOwner),
+            next_order_id: 4,
+        };
+        let key = BalanceKey { account_id: acc("alice.near"), token_id: acc("quote.near") };
+        state.balances.insert(&near_sdk::borsh::to_vec(&key).unwrap(), &500);
+        let legacy = [
+            legacy_order(0, "alice.near", Side::Sell, 10, 12, OrderStatus::Open),
+            // Unreduced, so it only shares order 0's level once migrated
+            OrderV0 { price_num: U128(24), price_den: U128(2), ..legacy_order(1, "alice.near", Side::Sell, 5, 12, OrderStatus::Open) },
+            legacy_order(2, "bob.near", Side::Buy, 7, 10, OrderStatus::Open),
+            legacy_order(3, "bob.near", Side::Buy, 3, 9, OrderStatus::Cancelled),
+        ];
+        for order in legacy {
+            let mut set = state.orders_by_owner.get(&order.owner_id).unwrap_or_else(|| {
+                let prefix = [b"ob:".as_slice(), &env::sha256(order.owner_id.as_bytes())].concat();
+                UnorderedSet::new(near_sdk::borsh::to_vec(&StorageKey::OrdersByOwnerSet { account_hash: prefix }).unwrap())
+            });
+            set.insert(&order.id);
+            state.orders_by_owner.insert(&order.owner_id, &set);
+            state.orders.insert(&order.id, &order);
+        }
+        write_state(&state);
+    }
+
+    #[test]
+    fn migrate_from_v0() {
+        context("ob.near");
+        write_v0_state();
+        let contract = Contract::migrate();
+        assert_eq!(env::storage_read(VERSION_KEY), Some(vec![STATE_VERSION]));
+        let markets = contract.get_markets(0, 10);
+        assert_eq!(markets.len(), 1);
+        assert_eq!((markets[0].market_id, &markets[0].base_token_id), (0, &acc("base.near")));
+        assert_eq!(markets[0].quote_token_id, acc("quote.near"));
+        assert_eq!(contract.get_owner(), acc("ob.near"));
+        assert_eq!(contract.get_status(), "running");
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("quote.near")).0, 500);
+        assert_eq!(contract.get_orders(0, 10).len(), 3);
+        assert!(contract.get_order(3).is_none());
+        let bob: Vec<u64> = contract.get_orders_by_owner(acc("bob.near")).iter().map(|o| o.id).collect();
+        assert_eq!(bob, vec![2]);
+        let order = contract.get_order(0).unwrap();
+        assert_eq!(order.status, "open");
+        assert_eq!(order.market_id, 0);
+        assert_eq!(order.time_in_force, "gtc");
+        assert_eq!(order.locked_base_remaining.0, 10);
+        let order = contract.get_order(1).unwrap();
+        assert_eq!((order.price_num.0, order.price_den.0), (12, 1));
+
+        let ask = contract.get_best_ask(0).unwrap();
+        assert_eq!((ask.price_num.0, ask.total_base.0, ask.order_count), (12, 15, 2));
+        assert_eq!(contract.get_level_orders(0, "sell".into(), U128(12), U128(1), 10), vec![0, 1]);
+        assert_eq!(contract.get_best_bid(0).unwrap().total_base.0, 7);
+        assert_eq!(contract.next_order_id, 4);
+    }
+
+    #[test]
+    fn migrated_orders_release_no_storage() {
+        context("ob.near");
+        write_v0_state();
+        let mut contract = Contract::migrate();
+        register(&mut contract, "alice.near");
+        context("alice.near");
+        contract.cancel_order(0);
+        // Only the closed order record is charged; the migrated order was never
+        let used = contract.storage_accounts.get(&acc("alice.near")).unwrap().used_bytes;
+        assert!(used > contract.account_storage_bytes());
+    }
+
+    #[test]
+    fn migrated_order_fills_after_its_owner_registers_with_the_minimum() {
+        context("ob.near");
+        write_v0_state();
+        let mut contract = Contract::migrate();
+        // Alice's migrated balance already puts her past the minimum
+        let min = contract.storage_balance_bounds().min;
+        register_with_deposit(&mut contract, "alice.near", min);
+        register(&mut contract, "bob.near");
+        contract.internal_add_balance(&acc("bob.near"), &acc("quote.near"), 120);
+        context("bob.near");
+        contract.place_order(0, "buy".into(), U128(10), Some(U128(120)), U128(12), U128(1), None, None, None, None, None, None);
+        assert!(contract.get_order(0).is_none());
+        assert_eq!(contract.get_balance(acc("alice.near"), acc("quote.near")).0, 620);
+        assert_eq!(contract.get_balance(acc("bob.near"), acc("base.near")).0, 10);
+    }
+
+    #[test]
+    fn migrate_keeps_current_layout() {
+        let (mut contract, market_id) = new_market();
+        let key = BalanceKey { account_id: acc("alice.near"), token_id: acc("base.near") };
+        contract.balances.insert(&near_sdk::borsh::to_vec(&key).unwrap(), &100);
+        register(&mut contract, "alice.near");
+        context("alice.near");
+        let id = contract.place_order(market_id, "sell".into(), U128(40), None, U128(3), U128(2), None, None, None, None, None, None);
+        write_state(&contract);
+
+        context("ob.near");
+        let migrated = Contract::migrate();
+        assert_eq!(migrated.get_state_version(), STATE_VERSION);
+        assert_eq!(migrated.get_owner(), acc("owner.near"));
+        assert_eq!(migrated.get_balance(acc("alice.near"), acc("base.near")).0, 60);
+        let order = migrated.get_order(id).unwrap();
+        assert_eq!((order.remaining_base.0, order.status.as_str()), (40, "open"));
+        let ask = migrated.get_best_ask(market_id).unwrap();
+        assert_eq!((ask.price_num.0, ask.price_den.0, ask.total_base.0), (3, 2, 40));
+        assert_eq!(migrated.next_order_id, id + 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot deserialize the contract state.")]
+    fn migrate_rejects_unknown_layout() {
+        context("ob.near");
+        write_state(&(1u8, 2u8));
+        Contract::migrate();
+    }
+}
@@ -17,11 +17,13 @@ interface OrderEvent {
 
 interface OrderView {
   id: number;
+  market_id: number;
   owner_id: string;
   side: 'Buy' | 'Sell' | 'buy' | 'sell';
   price_num: string | number;
   price_den: string | number;
   remaining_base: string | number;
+  status: 'open' | 'filled' | 'cancelled';
 }
 
 type Price = { num: bigint; den: bigint };
@@ -69,7 +71,7 @@ async function fetchRecentOrders(account: Account): Promise<OrderView[]> {
     methodName: 'get_orders',
     args: { from_index: 0, limit: 200 },
   });
-  return orders.filter(o => (o as any).status === 'Open' || (o as any).status?.Open === undefined);
+  return orders.filter(o => o.status === 'open');
 }
 
 function pickMatch(ob: OrderbookLocal): { makerId: number; takerId: number; baseFill: bigint; quotePaid: bigint } | null {
@@ -80,6 +82,8 @@ function pickMatch(ob: OrderbookLocal): { makerId: number; takerId: number; base
   buys.sort((a,b)=> Number(BigInt(b.price_num as any) * 1_000000000000000000n / BigInt(b.price_den as any) - BigInt(a.price_num as any) * 1_000000000000000000n / BigInt(a.price_den as any)));
   for (const s of sells) {
     for (const b of buys) {
+      // execute only fills orders of the same market
+      if (s.market_id !== b.market_id) continue;
       const sP: Price = { num: BigInt(s.price_num as any), den: BigInt(s.price_den as any) };
       const bP: Price = { num: BigInt(b.price_num as any), den: BigInt(b.price_den as any) };
       // Cross if buy price >= sell price
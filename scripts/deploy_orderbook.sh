@@ -4,9 +4,12 @@ set -euo pipefail
 CONTRACT_ACC=${CONTRACT_ACC:-gloomyswamp.testnet}
 BASE_TOKEN=${BASE_TOKEN:-frog.gloomyswamp.testnet}
 QUOTE_TOKEN=${QUOTE_TOKEN:-toad.gloomyswamp.testnet}
+OWNER=${OWNER:-$CONTRACT_ACC}
 
 # Build and deploy using cargo-near (non-reproducible wasm), then initialize
 cargo near deploy build-non-reproducible-wasm "$CONTRACT_ACC"
-near call "$CONTRACT_ACC" new '{"base_token_id":"'$BASE_TOKEN'","quote_token_id":"'$QUOTE_TOKEN'"}' --accountId "$CONTRACT_ACC"
+near call "$CONTRACT_ACC" new '{"owner_id":"'$OWNER'"}' --accountId "$CONTRACT_ACC"
+# List the first market (id 0)
+near call "$CONTRACT_ACC" add_market '{"base_token_id":"'$BASE_TOKEN'","quote_token_id":"'$QUOTE_TOKEN'"}' --accountId "$OWNER" --depositYocto 1
 
-echo "Deployed to $CONTRACT_ACC with base=$BASE_TOKEN quote=$QUOTE_TOKEN"
+echo "Deployed to $CONTRACT_ACC with base=$BASE_TOKEN quote=$QUOTE_TOKEN owner=$OWNER"
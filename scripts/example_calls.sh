@@ -6,19 +6,25 @@ BASE=${BASE:-frog.gloomyswamp.testnet}
 QUOTE=${QUOTE:-toad.gloomyswamp.testnet}
 TRADER=${TRADER:-gloomyswamp.testnet}
 
+# Register with the orderbook (NEP-145); the deposit also covers stored orders
+near call $CONTRACT storage_deposit '{"account_id":null,"registration_only":false}' --accountId $TRADER --deposit 0.1
+
 # Deposit 10 base and 100 quote (adjust decimals to your tokens)
 near call $BASE ft_transfer_call '{"receiver_id":"'$CONTRACT'","amount":"10000000000000000000000000","msg":""}' --accountId $TRADER --depositYocto 1 --gas 100000000000000
 near call $QUOTE ft_transfer_call '{"receiver_id":"'$CONTRACT'","amount":"100000000000000000000000000","msg":""}' --accountId $TRADER --depositYocto 1 --gas 100000000000000
 
+# Or deposit and place a sell order in one transaction
+# near call $BASE ft_transfer_call '{"receiver_id":"'$CONTRACT'","amount":"1000000000000000000000000","msg":"{\"action\":\"place_order\",\"market_id\":0,\"side\":\"sell\",\"price_num\":\"10\",\"price_den\":\"1\"}"}' --accountId $TRADER --depositYocto 1 --gas 100000000000000
+
 # View balances
 near view $CONTRACT get_balance '{"account_id":"'$TRADER'","token_id":"'$BASE'"}'
 near view $CONTRACT get_balance '{"account_id":"'$TRADER'","token_id":"'$QUOTE'"}'
 
 # Place a sell order: sell 5 base at price 10 quote per 1 base
-near call $CONTRACT place_order '{"side":"Sell","amount_base":"5000000000000000000000000","max_spend_quote":null,"price_num":"10","price_den":"1"}' --accountId $TRADER --depositYocto 1 --gas 100000000000000
+near call $CONTRACT place_order '{"market_id":0,"side":"Sell","amount_base":"5000000000000000000000000","max_spend_quote":null,"price_num":"10","price_den":"1"}' --accountId $TRADER --depositYocto 1 --gas 100000000000000
 
 # Place a buy order: buy up to 5 base paying at most 50 quote @ 10/1
-near call $CONTRACT place_order '{"side":"Buy","amount_base":"5000000000000000000000000","max_spend_quote":"50000000000000000000000000","price_num":"10","price_den":"1"}' --accountId $TRADER --depositYocto 1 --gas 100000000000000
+near call $CONTRACT place_order '{"market_id":0,"side":"Buy","amount_base":"5000000000000000000000000","max_spend_quote":"50000000000000000000000000","price_num":"10","price_den":"1"}' --accountId $TRADER --depositYocto 1 --gas 100000000000000
 
 # View orders
 near view $CONTRACT get_orders '{"from_index":0,"limit":20}'